@@ -1,15 +1,55 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
 use rust_stemmers::Stemmer;
 use serde::{Deserialize, Serialize};
 
+use crate::query_engine::feedback::FeedbackModel;
+
 pub const MAX_ARTICLE_DIR_SIZE: usize = 1000;
 pub const MAX_POSTINGS_LIST_SIZE: usize = 10000;
 pub const MAX_POSTINGS_LIST_DIRECTORY_SIZE: usize = 1000;
 pub const B: f64 = 0.75;
 pub const K1: f64 = 1.2;
+pub const DIRICHLET_MU: f64 = 2000.0;
+pub const DFR_C: f64 = 1.0;
 pub const K2: f64 = 100.0;
 pub const SNIPPET_OFFSET: usize = 50;
+pub const SYNONYM_WEIGHT: f64 = 0.5;
+pub const EXACT_MATCH_WEIGHT: f64 = 0.5;
+pub const EXACT_TOKEN_MARKER: char = '=';
+pub const PROXIMITY_WEIGHT: f64 = 1.0;
+pub const TITLE_WEIGHT: f64 = 2.0;
+pub const CATEGORY_WEIGHT: f64 = 1.5;
+pub const ANCHOR_WEIGHT: f64 = 1.5;
+pub const MAX_WILDCARD_EXPANSIONS: usize = 50;
+pub const MAX_WILDCARD_SCANNED_TERMS: usize = 100000;
+pub const MAX_FUZZY_DISTANCE: u8 = 2;
+pub const MAX_FUZZY_EXPANSIONS: usize = 10;
+pub const FUZZY_MATCH_WEIGHT: f64 = 0.5;
+pub const SUGGESTION_MAX_HITS: usize = 2;
+pub const MAX_SUGGESTION_CANDIDATES: usize = 10;
+pub const RERANK_CANDIDATES: usize = 100;
+pub const FEEDBACK_DOCS: usize = 10;
+pub const FEEDBACK_TERMS: usize = 10;
+pub const FEEDBACK_WEIGHT: f64 = 0.5;
+// Fraction of the articles a token can occur in and still be used as an expansion term
+pub const FEEDBACK_MAX_DOC_FREQ: f64 = 0.5;
+// Tokens in fewer articles don't say anything about which words occur together
+pub const LSA_MIN_DOC_FREQ: usize = 2;
+pub const LSA_MAX_TERMS: usize = 50000;
+pub const LSA_OVERSAMPLING: usize = 10;
+pub const LSA_POWER_ITERATIONS: usize = 2;
+pub const MINHASH_SIZE: usize = 64;
+pub const SHINGLE_SIZE: usize = 3;
+// Estimated Jaccard similarity of two articles' shingles above which they're near-duplicates
+pub const DUPLICATE_SIMILARITY: f64 = 0.8;
+// Articles with IDs in the same range of this size share score upper bounds when pruning
+pub const BLOCK_SIZE: usize = 1024;
+// Relative slack on score upper bounds, so rounding never prunes an article that should be kept
+pub const PRUNING_TOLERANCE: f64 = 1e-9;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Article {
@@ -32,59 +72,478 @@ impl Article {
     }
 }
 
+// Anchors are the texts of the links pointing to an article from other articles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Title,
+    Text,
+    Category,
+    Anchor,
+}
+
+impl Field {
+    pub const ALL: [Field; 4] = [Field::Title, Field::Text, Field::Category, Field::Anchor];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Text => "text",
+            Field::Category => "category",
+            Field::Anchor => "anchor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Field> {
+        Field::ALL.into_iter().find(|field| field.name() == name)
+    }
+
+    // How much a match in this field counts towards an article's score, unless the index config
+    // says otherwise
+    pub fn default_weight(&self) -> f64 {
+        match self {
+            Field::Title => TITLE_WEIGHT,
+            Field::Text => 1.0,
+            Field::Category => CATEGORY_WEIGHT,
+            Field::Anchor => ANCHOR_WEIGHT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FieldConfig {
+    pub weight: f64,
+    // How much term frequencies are normalized by the field length, from 0 (not at all) to 1
+    pub b: f64,
+}
+
+// Written to config.json in the index directory when the index is built. It holds the default
+// ranking parameters, and can be edited afterwards to tune them without rebuilding the index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexConfig {
+    pub fields: BTreeMap<Field, FieldConfig>,
+    #[serde(default = "default_k1")]
+    pub k1: f64,
+    #[serde(default = "default_k2")]
+    pub k2: f64,
+    // Number of characters shown on each side of the match in snippets
+    #[serde(default = "default_snippet_offset")]
+    pub snippet_offset: usize,
+}
+
+fn default_k1() -> f64 {
+    K1
+}
+
+fn default_k2() -> f64 {
+    K2
+}
+
+fn default_snippet_offset() -> usize {
+    SNIPPET_OFFSET
+}
+
+// Ranking parameters for a single query, from the command line, a config file or the caller.
+// Unset ones fall back to the index config, and `b` overrides the `b` of every field.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RankingParams {
+    pub k1: Option<f64>,
+    pub b: Option<f64>,
+    pub k2: Option<f64>,
+    pub snippet_offset: Option<usize>,
+    // Pseudo-relevance feedback, off unless a model is set
+    pub feedback: Option<FeedbackModel>,
+    pub feedback_docs: Option<usize>,
+    pub feedback_terms: Option<usize>,
+    pub feedback_weight: Option<f64>,
+    // Weight of the LSA cosine similarity when blending it with the ranking model's score
+    pub semantic_weight: Option<f64>,
+    // Whether near-duplicate results are folded into the best ranked one, on by default
+    pub collapse_duplicates: Option<bool>,
+}
+
+impl RankingParams {
+    pub fn load(config_path: &str) -> Result<Self, String> {
+        let config_file = std::fs::File::open(config_path)
+            .map_err(|e| format!("Failed to open config file: {e}"))?;
+        serde_json::from_reader(config_file)
+            .map_err(|e| format!("Failed to parse config file: {e}"))
+    }
+
+    // The parameters set in `other` take precedence
+    pub fn merge(&self, other: &RankingParams) -> RankingParams {
+        RankingParams {
+            k1: other.k1.or(self.k1),
+            b: other.b.or(self.b),
+            k2: other.k2.or(self.k2),
+            snippet_offset: other.snippet_offset.or(self.snippet_offset),
+            feedback: other.feedback.or(self.feedback),
+            feedback_docs: other.feedback_docs.or(self.feedback_docs),
+            feedback_terms: other.feedback_terms.or(self.feedback_terms),
+            feedback_weight: other.feedback_weight.or(self.feedback_weight),
+            semantic_weight: other.semantic_weight.or(self.semantic_weight),
+            collapse_duplicates: other.collapse_duplicates.or(self.collapse_duplicates),
+        }
+    }
+}
+
+impl IndexConfig {
+    pub fn new() -> Self {
+        IndexConfig {
+            fields: Field::ALL
+                .into_iter()
+                .map(|field| {
+                    (
+                        field,
+                        FieldConfig {
+                            weight: field.default_weight(),
+                            b: B,
+                        },
+                    )
+                })
+                .collect(),
+            k1: K1,
+            k2: K2,
+            snippet_offset: SNIPPET_OFFSET,
+        }
+    }
+
+    pub fn load(index_path: &Path) -> Result<Self, String> {
+        let config_file = std::fs::File::open(index_path.join("config.json"))
+            .map_err(|e| format!("Failed to open config.json file: {e}"))?;
+        serde_json::from_reader(config_file)
+            .map_err(|e| format!("Failed to parse config.json file: {e}"))
+    }
+
+    pub fn write(&self, index_path: &Path) -> Result<(), String> {
+        let config_file = std::fs::File::create(index_path.join("config.json"))
+            .map_err(|e| format!("Error opening config.json file: {e}"))?;
+        serde_json::to_writer_pretty(config_file, self)
+            .map_err(|e| format!("Error writing to config.json file: {e}"))
+    }
+
+    // The config with the query's ranking parameters applied on top
+    pub fn with_params(&self, params: &RankingParams) -> IndexConfig {
+        IndexConfig {
+            fields: Field::ALL
+                .into_iter()
+                .map(|field| {
+                    let mut field_config = self.field(field);
+                    field_config.b = params.b.unwrap_or(field_config.b);
+                    (field, field_config)
+                })
+                .collect(),
+            k1: params.k1.unwrap_or(self.k1),
+            k2: params.k2.unwrap_or(self.k2),
+            snippet_offset: params.snippet_offset.unwrap_or(self.snippet_offset),
+        }
+    }
+
+    // Fields missing from the config use the defaults
+    pub fn field(&self, field: Field) -> FieldConfig {
+        self.fields.get(&field).copied().unwrap_or(FieldConfig {
+            weight: field.default_weight(),
+            b: B,
+        })
+    }
+}
+
+// Latent semantic analysis vectors trained on the text of the articles. Articles are the projections
+// of their TF-IDF vectors, so queries are compared with them by projecting theirs the same way.
+// The article vectors are written to lsa_vectors.bin, `dims` values per article.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LsaModel {
+    pub dims: usize,
+    // Token ID -> IDF and vector
+    pub terms: HashMap<usize, (f32, Vec<f32>)>,
+}
+
+impl LsaModel {
+    pub fn load(index_path: &Path) -> Result<Self, String> {
+        let lsa_file = std::fs::File::open(index_path.join("lsa.bin"))
+            .map_err(|e| format!("Failed to open lsa.bin, build the index with --lsa-dims: {e}"))?;
+        bincode::deserialize_from(std::io::BufReader::new(lsa_file))
+            .map_err(|e| format!("Failed to parse lsa.bin: {e}"))
+    }
+
+    pub fn write(&self, index_path: &Path) -> Result<(), String> {
+        let lsa_file = std::fs::File::create(index_path.join("lsa.bin"))
+            .map_err(|e| format!("Error opening lsa.bin file: {e}"))?;
+        bincode::serialize_into(std::io::BufWriter::new(lsa_file), self)
+            .map_err(|e| format!("Error writing to lsa.bin file: {e}"))
+    }
+
+    // Sum of the vectors of the tokens, weighted by the given weights and their IDFs
+    pub fn project(&self, token_weights: &[(usize, f64)]) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dims];
+        for (token_id, weight) in token_weights {
+            if let Some((idf, term_vector)) = self.terms.get(token_id) {
+                for (value, term_value) in vector.iter_mut().zip(term_vector) {
+                    *value += (*weight as f32) * idf * term_value;
+                }
+            }
+        }
+        vector
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    (dot / (norm_a * norm_b)) as f64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Posting {
+    pub frequency: usize,
+    // Token positions within the article, in increasing order
+    pub positions: Vec<usize>,
+}
+
+// What the postings of a term in a block of article IDs can score at most: ranking models score
+// higher frequencies higher and longer fields lower, so no article in the block scores above one
+// with the block's largest frequency and smallest field length and norm
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BlockMax {
+    pub frequency: usize,
+    pub field_length: usize,
+    pub field_norm: f64,
+}
+
+impl BlockMax {
+    pub fn add(&mut self, frequency: usize, field_length: usize, field_norm: f64) {
+        self.frequency = self.frequency.max(frequency);
+        self.field_length = self.field_length.min(field_length);
+        self.field_norm = self.field_norm.min(field_norm);
+    }
+}
+
+// Block -> upper bounds of the term's postings in it
+pub type BlockMaxes = BTreeMap<usize, BlockMax>;
+
+pub fn block_index(article_id: usize) -> usize {
+    article_id / BLOCK_SIZE
+}
+
+// Adds a posting to the upper bounds of its block
+pub fn add_block_max(
+    block_maxes: &mut BlockMaxes,
+    article_id: usize,
+    frequency: usize,
+    field_length: usize,
+    field_norm: f64,
+) {
+    block_maxes
+        .entry(block_index(article_id))
+        .or_insert(BlockMax {
+            frequency,
+            field_length,
+            field_norm,
+        })
+        .add(frequency, field_length, field_norm);
+}
+
 pub struct QueryResult {
     pub article_id: usize,
     pub title: String,
     pub snippet: String,
     pub score: f64,
+    // Learning to rank features, see FEATURE_NAMES
+    pub features: Vec<f64>,
+    // Number of lower ranked near-duplicates collapsed into this result
+    pub duplicates: usize,
 }
 
-pub fn tokenize(text: &String) -> Vec<String> {
-    let mut tokens = Vec::new();
-    let mut start = 0;
-    let text = text.to_lowercase().replace(|c: char| !c.is_ascii(), ""); // non-ascii chars were making things wonky
+// The results of a query, along with a spelling corrected version of it if there is one
+pub struct QueryResults {
+    pub results: Vec<QueryResult>,
+    // Number of articles matching the query, before near-duplicates are collapsed. When ranking
+    // skipped articles that couldn't make it into the results, it's only a lower bound.
+    pub total_hits: usize,
+    pub exact_total_hits: bool,
+    pub suggestion: Option<String>,
+}
+
+// Which of a query's ranked results to return
+#[derive(Debug, Clone, Copy)]
+pub struct Page {
+    // Number of results to skip
+    pub offset: usize,
+    pub limit: usize,
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
     let stemmer = Stemmer::create(rust_stemmers::Algorithm::English);
+    split_words(text)
+        .iter()
+        .map(|(_, word)| stemmer.stem(word).to_string())
+        .collect()
+}
 
-    for (i, c) in text.chars().enumerate() {
-        if !c.is_alphanumeric() {
-            if start != i {
-                tokens.push(stemmer.stem(&text[start..i]).to_string());
-            }
-            start = i + 1;
-        }
+// Unstemmed tokens, marked so they can live in the same term dictionary as the stemmed ones
+pub fn tokenize_exact(text: &str) -> Vec<String> {
+    split_words(text)
+        .iter()
+        .map(|(_, word)| exact_token(word))
+        .collect()
+}
+
+pub fn exact_token(word: &str) -> String {
+    format!("{EXACT_TOKEN_MARKER}{word}")
+}
+
+// Offsets in the text of every token, indexed by the token's position
+pub fn token_offsets(text: &str) -> Vec<usize> {
+    split_words(text)
+        .iter()
+        .map(|(offset, _)| *offset)
+        .collect()
+}
+
+// MinHash signature of the token shingles, each value being the smallest hash of any shingle under
+// a different hash function. Texts shorter than a shingle are a single shingle, and texts without
+// tokens have an empty signature.
+pub fn minhash_signature(tokens: &[String]) -> Vec<u64> {
+    let shingle_hashes = tokens
+        .windows(SHINGLE_SIZE.min(tokens.len()).max(1))
+        .map(|shingle| {
+            // FNV-1a, which unlike DefaultHasher is the same across Rust versions
+            shingle
+                .iter()
+                .flat_map(|token| token.bytes().chain(std::iter::once(b' ')))
+                .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+                    (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+                })
+        })
+        .collect::<Vec<u64>>();
+    if shingle_hashes.is_empty() {
+        return Vec::new();
     }
+    (0..MINHASH_SIZE as u64)
+        .map(|seed| {
+            shingle_hashes
+                .iter()
+                .map(|hash| splitmix64(hash ^ splitmix64(seed)))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
 
-    if start != text.len() {
-        tokens.push(stemmer.stem(&text[start..text.len()]).to_string());
+// Fraction of matching values, an estimate of the Jaccard similarity of the shingle sets
+pub fn minhash_similarity(a: &[u64], b: &[u64]) -> f64 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+    let num_matching = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    num_matching as f64 / a.len() as f64
+}
+
+// Deterministic pseudo-random numbers, so the same corpus always gives the same index
+pub struct Lcg(u64);
+
+impl Lcg {
+    pub fn new(seed: u64) -> Self {
+        Lcg(seed)
+    }
+
+    // The low bits of the state repeat quickly, so it's mixed before use
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        splitmix64(self.0)
+    }
+
+    // Uniform in [-1, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 52) as f64 - 1.0
     }
+}
 
-    tokens
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
-pub fn tokenize_with_positions(text: &String) -> HashMap<String, Vec<usize>> {
-    let mut tokens = HashMap::new();
+// Lowercased words along with their offsets in the text
+fn split_words(text: &str) -> Vec<(usize, String)> {
+    let mut words = Vec::new();
     let mut start = 0;
     let text = text.to_lowercase().replace(|c: char| !c.is_ascii(), ""); // non-ascii chars were making things wonky
-    let stemmer = Stemmer::create(rust_stemmers::Algorithm::English);
 
-    for (i, c) in text.chars().enumerate() {
+    for (i, c) in text.char_indices() {
         if !c.is_alphanumeric() {
             if start != i {
-                let positions = tokens
-                    .entry(stemmer.stem(&text[start..i]).to_string())
-                    .or_insert(Vec::new());
-                positions.push(start);
+                words.push((start, text[start..i].to_string()));
             }
             start = i + 1;
         }
     }
 
     if start != text.len() {
-        let positions = tokens
-            .entry(stemmer.stem(&text[start..text.len()]).to_string())
-            .or_insert(Vec::new());
-        positions.push(start);
+        words.push((start, text[start..text.len()].to_string()));
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| format!("w{i}")).collect()
     }
 
-    tokens
+    #[test]
+    fn minhash_similarity_estimates_the_shingle_overlap() {
+        let tokens = words(0..200);
+        let signature = minhash_signature(&tokens);
+        assert_eq!(signature.len(), MINHASH_SIZE);
+        assert_eq!(minhash_signature(&tokens), signature);
+        assert_eq!(minhash_similarity(&signature, &signature), 1.0);
+
+        // Changing one token changes the 3 shingles containing it, out of about 200
+        let mut near_duplicate = tokens.clone();
+        near_duplicate[100] = "changed".to_string();
+        let similarity = minhash_similarity(&signature, &minhash_signature(&near_duplicate));
+        assert!(similarity > 0.85 && similarity < 1.0, "{similarity}");
+
+        // Texts sharing half of their shingles have a Jaccard similarity of about a third
+        let half = minhash_similarity(&signature, &minhash_signature(&words(100..300)));
+        assert!((half - 1.0 / 3.0).abs() < 0.2, "{half}");
+
+        // The same tokens in another order have no shingles in common
+        let reversed = tokens.iter().rev().cloned().collect::<Vec<String>>();
+        let similarity = minhash_similarity(&signature, &minhash_signature(&reversed));
+        assert!(similarity < 0.1, "{similarity}");
+    }
+
+    #[test]
+    fn short_texts_still_get_a_signature() {
+        assert_eq!(minhash_signature(&words(0..2)).len(), MINHASH_SIZE);
+        assert_eq!(
+            minhash_similarity(
+                &minhash_signature(&words(0..2)),
+                &minhash_signature(&words(0..2))
+            ),
+            1.0
+        );
+
+        let empty = minhash_signature(&[]);
+        assert!(empty.is_empty());
+        assert_eq!(minhash_similarity(&empty, &empty), 0.0);
+        assert_eq!(
+            minhash_similarity(&minhash_signature(&words(0..10)), &[1, 2, 3]),
+            0.0
+        );
+    }
 }
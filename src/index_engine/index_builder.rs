@@ -1,122 +1,465 @@
-use std::{collections::HashMap, io::Write, path::Path};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
 
-use crate::common::{tokenize, Article, MAX_POSTINGS_LIST_DIRECTORY_SIZE, MAX_POSTINGS_LIST_SIZE};
+use fst::MapBuilder;
+
+use super::lsa;
+use crate::{
+    common::{
+        add_block_max, minhash_signature, tokenize, tokenize_exact, Article, BlockMaxes, Field,
+        IndexConfig, LsaModel, Posting, LSA_MAX_TERMS, LSA_MIN_DOC_FREQ, MAX_ARTICLE_DIR_SIZE,
+        MAX_POSTINGS_LIST_DIRECTORY_SIZE, MAX_POSTINGS_LIST_SIZE, MINHASH_SIZE,
+    },
+    index_files::{ArticleIds, Column, Docstore, FixedWidth, PostingsFile},
+};
 
 pub struct IndexBuilder {
     cur_token_id: usize,
-    id_to_token: HashMap<usize, String>,
     token_to_id: HashMap<String, usize>,
     index_path: String,
-    inv_index: HashMap<usize, Vec<(usize, usize)>>,
-    article_lengths: HashMap<usize, usize>,
+    inv_index: HashMap<(Field, usize), Vec<(usize, Posting)>>,
+    // Upper bounds of every postings list's blocks, kept whole while the lists are flushed
+    block_maxes: HashMap<(Field, usize), BlockMaxes>,
+    // Field -> article ID -> number of tokens in the field
+    field_lengths: HashMap<Field, HashMap<usize, usize>>,
+    // Field -> article ID -> length of the field's log-scaled term frequency vector
+    field_norms: HashMap<Field, HashMap<usize, f64>>,
+    // Normalized title -> article ID, to resolve links
+    title_ids: HashMap<String, usize>,
+    // Normalized title -> texts of the links pointing to it
+    anchor_texts: HashMap<String, Vec<String>>,
+    // Article ID -> number of links pointing to it
+    inlinks: HashMap<usize, usize>,
+    // Article ID -> MinHash signature of its text, to find near-duplicates
+    minhashes: HashMap<usize, Vec<u64>>,
+    // Number of LSA dimensions, no LSA model is trained if None
+    lsa_dims: Option<usize>,
+    // Article ID and token ID -> frequency in the text of every article, for training LSA
+    lsa_term_freqs: Vec<(usize, HashMap<usize, usize>)>,
 }
 
 impl IndexBuilder {
-    pub fn new(index_path: &String) -> Result<Self, String> {
-        std::fs::create_dir_all(&index_path)
+    pub fn new(index_path: &str, lsa_dims: Option<usize>) -> Result<Self, String> {
+        std::fs::create_dir_all(index_path)
             .map_err(|e| format!("Error creating index directory: {e}"))?;
 
         Ok(IndexBuilder {
             cur_token_id: 0,
-            id_to_token: HashMap::new(),
             token_to_id: HashMap::new(),
-            index_path: index_path.clone(),
+            index_path: index_path.to_string(),
             inv_index: HashMap::new(),
-            article_lengths: HashMap::new(),
+            block_maxes: HashMap::new(),
+            field_lengths: HashMap::new(),
+            field_norms: HashMap::new(),
+            title_ids: HashMap::new(),
+            anchor_texts: HashMap::new(),
+            inlinks: HashMap::new(),
+            minhashes: HashMap::new(),
+            lsa_dims,
+            lsa_term_freqs: Vec::new(),
         })
     }
 
-    pub fn build_index(&mut self, article: &Article) {
-        let tokens = tokenize(&article.text);
-        let token_ids = self.get_token_ids(&tokens);
-        let word_counts = self.count_words(&token_ids);
-        self.update_inv_index(article.id, &word_counts);
-        self.article_lengths.insert(article.id, tokens.len());
+    // Returns the article as it was indexed, with its category links left out of the text. That's
+    // what the docstore keeps, so the text field's positions point into the stored text.
+    pub fn build_index(&mut self, article: &Article) -> Article {
+        // Categories are only indexed in their own field
+        let (text, categories, links) = parse_links(&article.text);
+        self.title_ids
+            .insert(normalize_title(&article.title), article.id);
+        for (target, anchor_text) in links {
+            self.anchor_texts
+                .entry(target)
+                .or_default()
+                .push(anchor_text);
+        }
+
+        self.index_field(article.id, Field::Text, &text);
+        self.minhashes
+            .insert(article.id, minhash_signature(&tokenize(&text)));
+        if self.lsa_dims.is_some() {
+            let mut term_freqs = HashMap::new();
+            for token in tokenize(&text) {
+                *term_freqs.entry(self.get_token_id(&token)).or_insert(0) += 1;
+            }
+            self.lsa_term_freqs.push((article.id, term_freqs));
+        }
+        self.index_field(article.id, Field::Title, &article.title);
+        self.index_field(article.id, Field::Category, &categories.join("\n"));
+        // Filled in by index_anchors once every article is in
+        self.index_field(article.id, Field::Anchor, "");
+        Article {
+            text,
+            ..article.clone()
+        }
+    }
+
+    // Links point to articles by title, so anchor texts can only be indexed after every article
+    pub fn index_anchors(&mut self) {
+        let anchor_texts = std::mem::take(&mut self.anchor_texts);
+        for (title, anchor_texts) in anchor_texts {
+            if let Some(article_id) = self.title_ids.get(&title).copied() {
+                self.inlinks.insert(article_id, anchor_texts.len());
+                self.index_field(article_id, Field::Anchor, &anchor_texts.join("\n"));
+            }
+        }
     }
 
-    pub async fn write_lexicon(&self) -> Result<(), String> {
-        let lexicon_path = Path::new(&self.index_path).join("lexicon.bin");
-        let mut file = std::fs::OpenOptions::new()
+    // The term dictionary is an FST mapping every token to its ID, sorted so it can be searched by
+    // prefix or pattern. The number of articles each token ID appears in is written along with the
+    // postings.
+    pub fn write_term_dictionary(&self) -> Result<(), String> {
+        let mut tokens = self.token_to_id.iter().collect::<Vec<(&String, &usize)>>();
+        tokens.sort_unstable();
+
+        let term_dictionary_path = Path::new(&self.index_path).join("terms.fst");
+        let file = std::fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&lexicon_path)
+            .truncate(true)
+            .open(&term_dictionary_path)
             .map_err(|e| format!("Error opening file: {e}"))?;
-
-        bincode::serialize_into(&mut file, &self.id_to_token)
-            .map_err(|e| format!("Error writing to lexicon file: {e}"))?;
-
-        Ok(())
+        let mut term_dictionary_builder = MapBuilder::new(std::io::BufWriter::new(file))
+            .map_err(|e| format!("Error creating term dictionary: {e}"))?;
+        for (token, token_id) in tokens {
+            term_dictionary_builder
+                .insert(token, *token_id as u64)
+                .map_err(|e| format!("Error writing to term dictionary: {e}"))?;
+        }
+        term_dictionary_builder
+            .finish()
+            .map_err(|e| format!("Error writing to term dictionary: {e}"))
     }
 
     pub fn update_all_inv_index_files(&mut self) -> Result<(), String> {
-        let token_ids = self.inv_index.keys().copied().collect::<Vec<usize>>(); // Create a copy of the token IDs
-        for token_id in token_ids {
-            self.update_inv_index_file(token_id)
+        let keys = self
+            .inv_index
+            .keys()
+            .copied()
+            .collect::<Vec<(Field, usize)>>(); // Create a copy of the field and token IDs
+        for (field, token_id) in keys {
+            self.update_inv_index_file(field, token_id)
                 .map_err(|e| format!("Error updating inverted index file: {e}"))?;
         }
         Ok(())
     }
 
-    pub fn write_article_lengths(&self) -> Result<(), String> {
-        let article_lengths_path = Path::new(&self.index_path).join("article_lengths.bin");
-        let mut file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&article_lengths_path)
+    // The postings lists flushed to "inv_index" while indexing are packed into one postings file
+    // per field along with their block upper bounds, once every postings list has been flushed
+    pub fn write_postings(&self) -> Result<(), String> {
+        for field in Field::ALL {
+            let lists = (0..self.cur_token_id).map(|token_id| {
+                let postings_list_path = self
+                    .postings_list_dir(field, token_id)
+                    .join(format!("{token_id}.txt"));
+                // Tokens don't necessarily appear in every field
+                let mut postings_list = if postings_list_path.exists() {
+                    read_postings_list_file(&postings_list_path)?
+                } else {
+                    Vec::new()
+                };
+                postings_list.sort_unstable_by_key(|(article_id, _)| *article_id);
+                Ok((self.block_maxes.get(&(field, token_id)), postings_list))
+            });
+            PostingsFile::write(Path::new(&self.index_path), field, lists)?;
+        }
+        remove_scratch_dir(&Path::new(&self.index_path).join("inv_index"))?;
+        self.write_doc_freqs()
+    }
+
+    // Number of articles each token ID appears in, in any field
+    fn write_doc_freqs(&self) -> Result<(), String> {
+        let postings_files = Field::ALL
+            .into_iter()
+            .map(|field| PostingsFile::open(Path::new(&self.index_path), field))
+            .collect::<Result<Vec<PostingsFile>, String>>()?;
+        let mut doc_freqs = Vec::with_capacity(self.cur_token_id);
+        for token_id in 0..self.cur_token_id {
+            let mut article_ids = Vec::new();
+            for postings_file in &postings_files {
+                if let Some(postings_list) = postings_file.list(token_id)? {
+                    article_ids.extend(postings_list.article_ids()?);
+                }
+            }
+            article_ids.sort_unstable();
+            article_ids.dedup();
+            doc_freqs.push(article_ids.len() as u32);
+        }
+        Column::write(
+            &Path::new(&self.index_path).join("doc_freqs.bin"),
+            doc_freqs,
+        )
+        .map_err(|e| format!("Error writing doc freqs: {e}"))
+    }
+
+    // Packs the articles written one file each while indexing into the docstore
+    pub fn write_docstore(&self) -> Result<(), String> {
+        let articles_path = Path::new(&self.index_path).join("articles");
+        let articles = self.article_ids().into_iter().map(|article_id| {
+            std::fs::read(
+                articles_path
+                    .join(format!("{}", article_id / MAX_ARTICLE_DIR_SIZE))
+                    .join(format!("article_{}.json", article_id)),
+            )
+            .map_err(|e| format!("Error reading article {article_id}: {e}"))
+        });
+        Docstore::write(Path::new(&self.index_path), articles)?;
+        remove_scratch_dir(&articles_path)
+    }
+
+    pub fn write_field_stats(&self) -> Result<(), String> {
+        let article_ids = self.article_ids();
+        ArticleIds::write(Path::new(&self.index_path), &article_ids)?;
+
+        let mut average_lengths = HashMap::new();
+        for field in Field::ALL {
+            let lengths = self.field_lengths.get(&field).cloned().unwrap_or_default();
+            self.write_article_stats(
+                &format!("{}_lengths.bin", field.name()),
+                &article_ids,
+                |article_id| vec![lengths.get(&article_id).copied().unwrap_or(0) as u32],
+            )?;
+            let average_length = lengths.values().sum::<usize>() as f64 / lengths.len() as f64;
+            average_lengths.insert(field, average_length);
+            let norms = self.field_norms.get(&field).cloned().unwrap_or_default();
+            self.write_article_stats(
+                &format!("{}_norms.bin", field.name()),
+                &article_ids,
+                |article_id| vec![norms.get(&article_id).copied().unwrap_or(0.0)],
+            )?;
+        }
+        let average_lengths_path = Path::new(&self.index_path).join("average_lengths.bin");
+        let file = std::fs::File::create(&average_lengths_path)
             .map_err(|e| format!("Error opening file: {e}"))?;
+        bincode::serialize_into(file, &average_lengths)
+            .map_err(|e| format!("Error writing to average_lengths.bin: {e}"))?;
+
+        self.write_article_stats("inlinks.bin", &article_ids, |article_id| {
+            vec![self.inlinks.get(&article_id).copied().unwrap_or(0) as u32]
+        })?;
+        // Articles too short for a signature get zeros, which no signature is in practice
+        self.write_article_stats("minhashes.bin", &article_ids, |article_id| {
+            match self.minhashes.get(&article_id) {
+                Some(signature) if !signature.is_empty() => signature.clone(),
+                _ => vec![0; MINHASH_SIZE],
+            }
+        })
+    }
 
-        bincode::serialize_into(&mut file, &self.article_lengths)
-            .map_err(|e| format!("Error writing to article lengths file: {e}"))?;
+    // Truncated SVD of the articles' log-scaled TF-IDF vectors over their text, keeping the most
+    // common tokens that occur in more than one article
+    pub fn write_lsa_model(&self) -> Result<(), String> {
+        let dims = match self.lsa_dims {
+            Some(dims) => dims,
+            None => return Ok(()),
+        };
+        let num_articles = self.lsa_term_freqs.len();
 
-        Ok(())
+        let mut doc_freqs: HashMap<usize, usize> = HashMap::new();
+        for (_, term_freqs) in &self.lsa_term_freqs {
+            for token_id in term_freqs.keys() {
+                *doc_freqs.entry(*token_id).or_insert(0) += 1;
+            }
+        }
+        let mut terms = doc_freqs
+            .into_iter()
+            .filter(|(_, doc_freq)| *doc_freq >= LSA_MIN_DOC_FREQ)
+            .collect::<Vec<(usize, usize)>>();
+        terms.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        terms.truncate(LSA_MAX_TERMS);
+        let term_indices = terms
+            .iter()
+            .enumerate()
+            .map(|(term_index, (token_id, _))| (*token_id, term_index))
+            .collect::<HashMap<usize, usize>>();
+        let idfs = terms
+            .iter()
+            .map(|(_, doc_freq)| (num_articles as f64 / *doc_freq as f64).ln())
+            .collect::<Vec<f64>>();
+
+        let log_freqs = self
+            .lsa_term_freqs
+            .iter()
+            .map(|(_, term_freqs)| {
+                term_freqs
+                    .iter()
+                    .map(|(token_id, freq)| (*token_id, 1.0 + (*freq as f64).ln()))
+                    .collect::<Vec<(usize, f64)>>()
+            })
+            .collect::<Vec<Vec<(usize, f64)>>>();
+        let matrix = log_freqs
+            .iter()
+            .map(|token_freqs| {
+                token_freqs
+                    .iter()
+                    .filter_map(|(token_id, freq)| {
+                        let term_index = *term_indices.get(token_id)?;
+                        Some((term_index, freq * idfs[term_index]))
+                    })
+                    .collect()
+            })
+            .collect::<Vec<Vec<(usize, f64)>>>();
+
+        let term_vectors = lsa::train(&matrix, terms.len(), dims);
+        // Fewer dimensions are kept when there are fewer terms or articles than asked for
+        let dims = term_vectors.first().map_or(0, Vec::len);
+        let lsa_model = LsaModel {
+            dims,
+            terms: terms
+                .iter()
+                .zip(idfs)
+                .zip(term_vectors)
+                .map(|(((token_id, _), idf), vector)| {
+                    let vector = vector.into_iter().map(|value| value as f32).collect();
+                    (*token_id, (idf as f32, vector))
+                })
+                .collect(),
+        };
+        lsa_model.write(Path::new(&self.index_path))?;
+
+        let article_vectors = self
+            .lsa_term_freqs
+            .iter()
+            .zip(&log_freqs)
+            .map(|((article_id, _), token_freqs)| (*article_id, lsa_model.project(token_freqs)))
+            .collect::<HashMap<usize, Vec<f32>>>();
+        self.write_article_stats("lsa_vectors.bin", &self.article_ids(), |article_id| {
+            article_vectors
+                .get(&article_id)
+                .cloned()
+                .unwrap_or_else(|| vec![0.0; dims])
+        })
+    }
+
+    pub fn write_index_config(&self) -> Result<(), String> {
+        IndexConfig::new().write(Path::new(&self.index_path))
+    }
+
+    // Every article is in the text field's lengths, even with an empty text
+    fn article_ids(&self) -> Vec<usize> {
+        let mut article_ids = self
+            .field_lengths
+            .get(&Field::Text)
+            .map(|lengths| lengths.keys().copied().collect::<Vec<usize>>())
+            .unwrap_or_default();
+        article_ids.sort_unstable();
+        article_ids
     }
 
-    fn get_token_ids(&mut self, tokens: &Vec<String>) -> Vec<usize> {
-        let mut token_ids = Vec::new();
-        for token in tokens {
-            token_ids.push(self.get_token_id(token));
+    // The values of every article, in the order of the article IDs
+    fn write_article_stats<T: FixedWidth>(
+        &self,
+        file_name: &str,
+        article_ids: &[usize],
+        stats: impl Fn(usize) -> Vec<T>,
+    ) -> Result<(), String> {
+        let stats_path = Path::new(&self.index_path).join(file_name);
+        Column::write(
+            &stats_path,
+            article_ids.iter().flat_map(|article_id| stats(*article_id)),
+        )
+        .map_err(|e| format!("Error writing to {file_name}: {e}"))
+    }
+
+    // Also records the number of tokens in the field and the length of its log-scaled term
+    // frequency vector, which the vector space model normalizes by
+    fn index_field(&mut self, article_id: usize, field: Field, text: &str) {
+        let tokens = tokenize(text);
+        let mut token_positions = HashMap::new();
+        self.add_token_positions(&tokens, &mut token_positions);
+        // Index the exact (unstemmed) form of every token alongside its stem, at the same positions
+        self.add_token_positions(&tokenize_exact(text), &mut token_positions);
+        let norm = token_positions
+            .values()
+            .map(|positions| (1.0 + (positions.len() as f64).ln()).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        self.update_inv_index(article_id, field, token_positions, tokens.len(), norm);
+        self.field_lengths
+            .entry(field)
+            .or_default()
+            .insert(article_id, tokens.len());
+        self.field_norms
+            .entry(field)
+            .or_default()
+            .insert(article_id, norm);
+    }
+
+    fn add_token_positions(
+        &mut self,
+        tokens: &[String],
+        token_positions: &mut HashMap<usize, Vec<usize>>,
+    ) {
+        for (position, token) in tokens.iter().enumerate() {
+            let token_id = self.get_token_id(token);
+            token_positions.entry(token_id).or_default().push(position);
         }
-        token_ids
     }
 
-    fn get_token_id(&mut self, token: &String) -> usize {
+    fn get_token_id(&mut self, token: &str) -> usize {
         match self.token_to_id.get(token) {
             Some(token_id) => *token_id,
             None => {
                 let token_id = self.cur_token_id;
-                self.id_to_token.insert(token_id, token.clone());
-                self.token_to_id.insert(token.clone(), token_id);
+                self.token_to_id.insert(token.to_string(), token_id);
                 self.cur_token_id += 1;
                 token_id
             }
         }
     }
 
-    fn count_words(&self, token_ids: &Vec<usize>) -> HashMap<usize, usize> {
-        let mut word_counts = HashMap::<usize, usize>::new();
-        for token_id in token_ids {
-            let count = word_counts.entry(*token_id).or_insert(0);
-            *count += 1;
-        }
-        word_counts
-    }
-
-    fn update_inv_index(&mut self, article_id: usize, word_counts: &HashMap<usize, usize>) {
-        for (token_id, count) in word_counts {
-            let token_postings_list = self.inv_index.entry(*token_id).or_insert(Vec::new());
-            token_postings_list.push((article_id, *count));
+    fn update_inv_index(
+        &mut self,
+        article_id: usize,
+        field: Field,
+        token_positions: HashMap<usize, Vec<usize>>,
+        field_length: usize,
+        field_norm: f64,
+    ) {
+        for (token_id, positions) in token_positions {
+            add_block_max(
+                self.block_maxes.entry((field, token_id)).or_default(),
+                article_id,
+                positions.len(),
+                field_length,
+                field_norm,
+            );
+            let token_postings_list = self.inv_index.entry((field, token_id)).or_default();
+            token_postings_list.push((
+                article_id,
+                Posting {
+                    frequency: positions.len(),
+                    positions,
+                },
+            ));
             if token_postings_list.len() >= MAX_POSTINGS_LIST_SIZE {
-                if let Err(e) = self.update_inv_index_file(*token_id) {
+                if let Err(e) = self.update_inv_index_file(field, token_id) {
                     eprintln!("Error updating inverted index file: {}", e);
                 }
             }
         }
     }
 
-    fn update_inv_index_file(&mut self, token_id: usize) -> Result<(), String> {
-        let subdir_path = Path::new(&self.index_path)
+    fn postings_list_dir(&self, field: Field, token_id: usize) -> PathBuf {
+        Path::new(&self.index_path)
             .join("inv_index")
-            .join(format!("{}", token_id / MAX_POSTINGS_LIST_DIRECTORY_SIZE));
+            .join(field.name())
+            .join(format!("{}", token_id / MAX_POSTINGS_LIST_DIRECTORY_SIZE))
+    }
+
+    fn update_inv_index_file(&mut self, field: Field, token_id: usize) -> Result<(), String> {
+        // Lists flushed at exactly MAX_POSTINGS_LIST_SIZE are left empty, with nothing to append
+        if self
+            .inv_index
+            .get(&(field, token_id))
+            .is_some_and(Vec::is_empty)
+        {
+            return Ok(());
+        }
+        let subdir_path = self.postings_list_dir(field, token_id);
 
         std::fs::create_dir_all(&subdir_path).map_err(|e| {
             format!(
@@ -125,16 +468,26 @@ impl IndexBuilder {
             )
         })?;
 
-        let token_postings_list = self
-            .inv_index
-            .get(&token_id)
-            .ok_or(format!("Token ID {token_id} not found in inverted index"))?;
+        let token_postings_list = self.inv_index.get(&(field, token_id)).ok_or(format!(
+            "Token ID {token_id} not found in the {} inverted index",
+            field.name()
+        ))?;
+        // Each line is "article_id frequency positions...", with every position stored as the
+        // gap from the previous one to keep the numbers (and the files) small
         let mut postings_list_string = token_postings_list
             .iter()
-            .map(|(article_id, count)| format!("{} {}", article_id, count))
+            .map(|(article_id, posting)| {
+                let mut line = format!("{} {}", article_id, posting.frequency);
+                let mut prev_position = 0;
+                for position in &posting.positions {
+                    line.push_str(&format!(" {}", position - prev_position));
+                    prev_position = *position;
+                }
+                line
+            })
             .collect::<Vec<String>>()
             .join("\n");
-        postings_list_string.push_str("\n");
+        postings_list_string.push('\n');
 
         let postings_list_path = subdir_path.join(format!("{}.txt", token_id));
         let mut file = std::fs::OpenOptions::new()
@@ -146,8 +499,126 @@ impl IndexBuilder {
             .map_err(|e| format!("Error writing to file: {e}"))?;
 
         // Clear postings list
-        self.inv_index.insert(token_id, Vec::new());
+        self.inv_index.insert((field, token_id), Vec::new());
 
         Ok(())
     }
 }
+
+// Returns the article's wikitext without its category links, its categories and the (normalized
+// target title, anchor text) of every link to another article
+fn parse_links(text: &str) -> (String, Vec<String>, Vec<(String, String)>) {
+    let mut body = String::with_capacity(text.len());
+    let mut categories = Vec::new();
+    let mut links = Vec::new();
+
+    let mut rest = text;
+    while let Some(start) = rest.find("[[") {
+        let end = match rest[start + 2..].find("]]") {
+            Some(end) => start + 2 + end,
+            None => break,
+        };
+        let link = &rest[start + 2..end];
+
+        let (target, anchor_text) = link.split_once('|').unwrap_or((link, link));
+        match target.split_once(':') {
+            Some((namespace, category)) if namespace.trim().eq_ignore_ascii_case("category") => {
+                categories.push(category.trim().to_string());
+                body.push_str(&rest[..start]);
+            }
+            // Files, other languages, etc.
+            Some(_) => body.push_str(&rest[..end + 2]),
+            None => {
+                links.push((normalize_title(target), anchor_text.trim().to_string()));
+                body.push_str(&rest[..end + 2]);
+            }
+        }
+        rest = &rest[end + 2..];
+    }
+    body.push_str(rest);
+
+    (body, categories, links)
+}
+
+// Links can use underscores for spaces, point to a section or differ in case from the title
+fn normalize_title(title: &str) -> String {
+    let title = title.split('#').next().unwrap_or_default();
+    title.replace('_', " ").trim().to_lowercase()
+}
+
+// Nothing is written to the scratch directories of an index without articles
+fn remove_scratch_dir(path: &Path) -> Result<(), String> {
+    if !path.exists() {
+        return Ok(());
+    }
+    std::fs::remove_dir_all(path).map_err(|e| format!("Error removing {}: {e}", path.display()))
+}
+
+// Reads back a postings list flushed by update_inv_index_file
+fn read_postings_list_file(postings_list_path: &Path) -> Result<Vec<(usize, Posting)>, String> {
+    let postings_list_file = std::fs::File::open(postings_list_path)
+        .map_err(|e| format!("Error opening postings list file: {e}"))?;
+    let mut postings_list = Vec::new();
+    for line in std::io::BufReader::new(postings_list_file).lines() {
+        let line = line.map_err(|e| format!("Error reading postings list file: {e}"))?;
+        let mut numbers = line.split_whitespace().map(|number| {
+            number
+                .parse::<usize>()
+                .map_err(|e| format!("Error parsing postings list file: {e}"))
+        });
+        let article_id = numbers.next().ok_or("Empty line in postings list file")??;
+        let frequency = numbers
+            .next()
+            .ok_or("Missing frequency in postings list file")??;
+
+        // Positions are stored as gaps from the previous position
+        let mut positions = Vec::with_capacity(frequency);
+        let mut position = 0;
+        for gap in numbers {
+            position += gap?;
+            positions.push(position);
+        }
+
+        postings_list.push((
+            article_id,
+            Posting {
+                frequency,
+                positions,
+            },
+        ));
+    }
+    Ok(postings_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn postings_lists_flushed_at_the_limit_round_trip() {
+        let index_path =
+            std::env::temp_dir().join(format!("wiki-search-flush-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&index_path);
+        let mut index_builder = IndexBuilder::new(index_path.to_str().unwrap(), None).unwrap();
+        let token_id = index_builder.get_token_id("planet");
+        for article_id in 0..MAX_POSTINGS_LIST_SIZE {
+            let token_positions = HashMap::from([(token_id, vec![article_id % 3])]);
+            index_builder.update_inv_index(article_id, Field::Text, token_positions, 3, 1.0);
+        }
+        index_builder.update_all_inv_index_files().unwrap();
+        index_builder.write_postings().unwrap();
+
+        let postings_file = PostingsFile::open(&index_path, Field::Text).unwrap();
+        let postings_list = postings_file.list(token_id).unwrap().unwrap();
+        assert_eq!(postings_list.doc_freq, MAX_POSTINGS_LIST_SIZE);
+        assert_eq!(
+            postings_list.article_ids().unwrap(),
+            (0..MAX_POSTINGS_LIST_SIZE).collect::<Vec<usize>>()
+        );
+        let block = &postings_list.blocks[0];
+        let articles = block.articles().unwrap();
+        assert_eq!(block.positions(&articles).unwrap()[4], vec![1]);
+
+        std::fs::remove_dir_all(&index_path).unwrap();
+    }
+}
@@ -19,12 +19,16 @@ enum Tag {
     Other,
 }
 
-pub async fn build_index(wiki_dump_path: &String, index_path: &String) -> Result<usize, String> {
+pub async fn build_index(
+    wiki_dump_path: &str,
+    index_path: &str,
+    lsa_dims: Option<usize>,
+) -> Result<usize, String> {
     let mut wiki_dump_file = std::fs::File::open(wiki_dump_path).unwrap();
     let mut reader = MultiBzDecoder::new(&mut wiki_dump_file);
     let parser = EventReader::new(&mut reader);
 
-    match parse_dump(parser, index_path).await {
+    match parse_dump(parser, index_path, lsa_dims).await {
         Err(e) => Err(format!("Error parsing dump: {}", e)),
         Ok(article_count) => Ok(article_count),
     }
@@ -32,13 +36,15 @@ pub async fn build_index(wiki_dump_path: &String, index_path: &String) -> Result
 
 async fn parse_dump(
     parser: EventReader<&mut MultiBzDecoder<&mut std::fs::File>>,
-    index_path: &String,
+    index_path: &str,
+    lsa_dims: Option<usize>,
 ) -> Result<usize, String> {
     let mut cur_tag = Tag::Other;
     let mut cur_article = Article::new();
 
     let index_builder = Arc::new(Mutex::new(
-        IndexBuilder::new(index_path).map_err(|e| format! {"Error creating index builder: {e}"})?,
+        IndexBuilder::new(index_path, lsa_dims)
+            .map_err(|e| format! {"Error creating index builder: {e}"})?,
     ));
 
     let mut article_count = 0;
@@ -68,7 +74,7 @@ async fn parse_dump(
                     tasks.push(index_article(
                         // All these clones are fairly cheap
                         cur_article.clone(),
-                        index_path.clone(),
+                        index_path.to_string(),
                         index_builder.clone(),
                     ));
 
@@ -91,10 +97,8 @@ async fn parse_dump(
                 Tag::Title => {
                     cur_article.title = chars;
                 }
-                Tag::Id => {
-                    if !cur_article.parsed_id() {
-                        cur_article.id = chars.parse::<usize>().unwrap();
-                    }
+                Tag::Id if !cur_article.parsed_id() => {
+                    cur_article.id = chars.parse::<usize>().unwrap();
                 }
                 Tag::Text => {
                     cur_article.text = chars;
@@ -117,18 +121,31 @@ async fn parse_dump(
         }
     }
 
+    index_builder.lock().await.index_anchors();
+
+    index_builder
+        .lock()
+        .await
+        .write_term_dictionary()
+        .map_err(|e| format!("Error writing term dictionary: {}", e))?;
+
     index_builder
         .lock()
         .await
-        .write_lexicon()
+        .write_field_stats()
+        .map_err(|e| format!("Error writing field stats: {}", e))?;
+
+    index_builder
+        .lock()
         .await
-        .map_err(|e| format!("Error writing lexicon: {}", e))?;
+        .write_lsa_model()
+        .map_err(|e| format!("Error writing LSA model: {}", e))?;
 
     index_builder
         .lock()
         .await
-        .write_article_lengths()
-        .map_err(|e| format!("Error writing article lengths: {}", e))?;
+        .write_index_config()
+        .map_err(|e| format!("Error writing index config: {}", e))?;
 
     index_builder
         .lock()
@@ -136,6 +153,18 @@ async fn parse_dump(
         .update_all_inv_index_files()
         .map_err(|e| format!("Error updating inverted index files: {}", e))?;
 
+    index_builder
+        .lock()
+        .await
+        .write_postings()
+        .map_err(|e| format!("Error writing postings: {}", e))?;
+
+    index_builder
+        .lock()
+        .await
+        .write_docstore()
+        .map_err(|e| format!("Error writing docstore: {}", e))?;
+
     Ok(article_count)
 }
 
@@ -144,10 +173,10 @@ async fn index_article(
     index_path: String,
     index_builder: Arc<Mutex<IndexBuilder>>,
 ) -> Result<(), String> {
+    let article = index_builder.lock().await.build_index(&article);
+
     snippet_engine::insert_article(&article, &index_path)
         .map_err(|e| format!("Error inserting article: {e}"))?;
 
-    index_builder.lock().await.build_index(&article);
-
     Ok(())
 }
@@ -0,0 +1,156 @@
+use crate::common::{Lcg, LSA_OVERSAMPLING, LSA_POWER_ITERATIONS};
+
+// Truncated SVD of the sparse term-article matrix, where `articles` holds the (term index, weight)
+// entries of every article's column. Returns the vector of every term in the top `dims` left
+// singular vectors, which articles and queries are projected onto.
+//
+// Uses a randomized range finder with power iterations (Halko et al.), so only a few passes over
+// the matrix are needed and the dense parts stay at num_terms * (dims + oversampling).
+pub fn train(articles: &[Vec<(usize, f64)>], num_terms: usize, dims: usize) -> Vec<Vec<f64>> {
+    let rank = (dims + LSA_OVERSAMPLING).min(num_terms).min(articles.len());
+    if rank == 0 {
+        return vec![Vec::new(); num_terms];
+    }
+
+    // Basis of the range of the matrix, one column per vector
+    let mut random = Lcg::new(0x2545_f491_4f6c_dd1d);
+    let mut basis = (0..rank)
+        .map(|_| {
+            let column = (0..articles.len())
+                .map(|_| random.next_f64())
+                .collect::<Vec<f64>>();
+            multiply(articles, &column, num_terms)
+        })
+        .collect::<Vec<Vec<f64>>>();
+    orthonormalize(&mut basis);
+    for _ in 0..LSA_POWER_ITERATIONS {
+        let mut article_basis = basis
+            .iter()
+            .map(|column| multiply_transpose(articles, column))
+            .collect::<Vec<Vec<f64>>>();
+        orthonormalize(&mut article_basis);
+        basis = article_basis
+            .iter()
+            .map(|column| multiply(articles, column, num_terms))
+            .collect();
+        orthonormalize(&mut basis);
+    }
+
+    // The left singular vectors are the basis rotated by the eigenvectors of B * B^T, where
+    // B = basis^T * matrix is small (rank x num_articles)
+    let projected = basis
+        .iter()
+        .map(|column| multiply_transpose(articles, column))
+        .collect::<Vec<Vec<f64>>>();
+    let gram = projected
+        .iter()
+        .map(|row| projected.iter().map(|other| dot(row, other)).collect())
+        .collect::<Vec<Vec<f64>>>();
+    let (eigenvalues, eigenvectors) = symmetric_eigen(gram);
+    let mut order = (0..rank).collect::<Vec<usize>>();
+    order.sort_by(|a, b| eigenvalues[*b].total_cmp(&eigenvalues[*a]));
+    order.truncate(dims);
+
+    (0..num_terms)
+        .map(|term| {
+            order
+                .iter()
+                .map(|component| {
+                    (0..rank)
+                        .map(|i| basis[i][term] * eigenvectors[i][*component])
+                        .sum()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Matrix * column, for a column with one entry per article
+fn multiply(articles: &[Vec<(usize, f64)>], column: &[f64], num_terms: usize) -> Vec<f64> {
+    let mut result = vec![0.0; num_terms];
+    for (entries, value) in articles.iter().zip(column) {
+        for (term, weight) in entries {
+            result[*term] += weight * value;
+        }
+    }
+    result
+}
+
+// Matrix^T * column, for a column with one entry per term
+fn multiply_transpose(articles: &[Vec<(usize, f64)>], column: &[f64]) -> Vec<f64> {
+    articles
+        .iter()
+        .map(|entries| {
+            entries
+                .iter()
+                .map(|(term, weight)| weight * column[*term])
+                .sum()
+        })
+        .collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+// Modified Gram-Schmidt. Columns that turn out linearly dependent are left as zeros.
+fn orthonormalize(columns: &mut [Vec<f64>]) {
+    for i in 0..columns.len() {
+        let (previous, rest) = columns.split_at_mut(i);
+        let column = &mut rest[0];
+        for other in previous.iter() {
+            let projection = dot(column, other);
+            for (value, other_value) in column.iter_mut().zip(other) {
+                *value -= projection * other_value;
+            }
+        }
+        let norm = dot(column, column).sqrt();
+        for value in column.iter_mut() {
+            *value = if norm > 1e-12 { *value / norm } else { 0.0 };
+        }
+    }
+}
+
+// Cyclic Jacobi eigenvalue algorithm for a small symmetric matrix. Returns the eigenvalues and the
+// matrix with the matching eigenvectors as its columns.
+fn symmetric_eigen(mut matrix: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = matrix.len();
+    let mut eigenvectors = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect::<Vec<Vec<f64>>>();
+
+    for _ in 0..100 {
+        let off_diagonal = (0..n)
+            .flat_map(|i| (0..n).filter(move |j| *j != i).map(move |j| (i, j)))
+            .map(|(i, j)| matrix[i][j] * matrix[i][j])
+            .sum::<f64>();
+        if off_diagonal < 1e-20 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if matrix[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (matrix[q][q] - matrix[p][p]) / (2.0 * matrix[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                // A' = J^T A J and V' = V J for the rotation J in the (p, q) plane
+                for row in matrix.iter_mut().chain(eigenvectors.iter_mut()) {
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = c * kp - s * kq;
+                    row[q] = s * kp + c * kq;
+                }
+                let (before_q, from_q) = matrix.split_at_mut(q);
+                for (pk, qk) in before_q[p].iter_mut().zip(from_q[0].iter_mut()) {
+                    let (old_pk, old_qk) = (*pk, *qk);
+                    *pk = c * old_pk - s * old_qk;
+                    *qk = s * old_pk + c * old_qk;
+                }
+            }
+        }
+    }
+
+    ((0..n).map(|i| matrix[i][i]).collect(), eigenvectors)
+}
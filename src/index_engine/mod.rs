@@ -1,3 +1,5 @@
 pub mod index_builder;
+#[allow(clippy::module_inception)]
 pub mod index_engine;
+pub mod lsa;
 pub mod snippet_engine;
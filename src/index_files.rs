@@ -0,0 +1,677 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    marker::PhantomData,
+    path::Path,
+    sync::Arc,
+};
+
+use memmap2::Mmap;
+
+use crate::common::{block_index, Article, BlockMax, BlockMaxes, Field, Posting, BLOCK_SIZE};
+
+// The files an index is searched from are laid out to be memory-mapped and read in place, so
+// opening an index doesn't load or deserialize them and processes searching the same index share
+// its pages. Fixed-width numbers are little-endian, postings lists are made of LEB128 varints.
+
+pub fn map_file(path: &Path) -> Result<Mmap, String> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let file = File::open(path).map_err(|e| format!("Failed to open {file_name}: {e}"))?;
+    // Index files are only written when the index is built, which can't happen while it's being
+    // searched without the files changing under the mapping
+    unsafe { Mmap::map(&file) }.map_err(|e| format!("Failed to map {file_name}: {e}"))
+}
+
+fn create_file(path: &Path) -> Result<BufWriter<File>, String> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| format!("Error opening file: {e}"))
+}
+
+pub trait FixedWidth: Copy {
+    const WIDTH: usize;
+    fn from_bytes(bytes: &[u8]) -> Self;
+    fn write_to(self, writer: &mut impl Write) -> std::io::Result<()>;
+}
+
+impl FixedWidth for u32 {
+    const WIDTH: usize = 4;
+    fn from_bytes(bytes: &[u8]) -> Self {
+        <[u8; 4]>::try_from(bytes).map_or(0, u32::from_le_bytes)
+    }
+    fn write_to(self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl FixedWidth for f32 {
+    const WIDTH: usize = 4;
+    fn from_bytes(bytes: &[u8]) -> Self {
+        <[u8; 4]>::try_from(bytes).map_or(0.0, f32::from_le_bytes)
+    }
+    fn write_to(self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl FixedWidth for u64 {
+    const WIDTH: usize = 8;
+    fn from_bytes(bytes: &[u8]) -> Self {
+        <[u8; 8]>::try_from(bytes).map_or(0, u64::from_le_bytes)
+    }
+    fn write_to(self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl FixedWidth for f64 {
+    const WIDTH: usize = 8;
+    fn from_bytes(bytes: &[u8]) -> Self {
+        <[u8; 8]>::try_from(bytes).map_or(0.0, f64::from_le_bytes)
+    }
+    fn write_to(self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+// A file of fixed-width values one after the other
+pub struct Column<T> {
+    mmap: Mmap,
+    values: PhantomData<T>,
+}
+
+impl<T: FixedWidth> Column<T> {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        Ok(Column {
+            mmap: map_file(path)?,
+            values: PhantomData,
+        })
+    }
+
+    pub fn num_values(&self) -> usize {
+        self.mmap.len() / T::WIDTH
+    }
+
+    pub fn get(&self, index: usize) -> Option<T> {
+        let start = index.checked_mul(T::WIDTH)?;
+        self.mmap.get(start..start + T::WIDTH).map(T::from_bytes)
+    }
+
+    pub fn write(path: &Path, values: impl IntoIterator<Item = T>) -> Result<(), String> {
+        let mut writer = create_file(path)?;
+        for value in values {
+            value
+                .write_to(&mut writer)
+                .map_err(|e| format!("Error writing to file: {e}"))?;
+        }
+        writer
+            .flush()
+            .map_err(|e| format!("Error writing to file: {e}"))
+    }
+}
+
+// The IDs of every article in increasing order, in article_ids.bin. The other per-article files
+// store each article's values at its position in it.
+pub struct ArticleIds(Column<u64>);
+
+impl ArticleIds {
+    pub fn open(index_path: &Path) -> Result<Self, String> {
+        Column::open(&index_path.join("article_ids.bin")).map(ArticleIds)
+    }
+
+    pub fn num_articles(&self) -> usize {
+        self.0.num_values()
+    }
+
+    fn position(&self, article_id: usize) -> Option<usize> {
+        let article_id = article_id as u64;
+        let (mut low, mut high) = (0, self.0.num_values());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.0.get(middle)?.cmp(&article_id) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(middle),
+            }
+        }
+        None
+    }
+
+    pub fn write(index_path: &Path, article_ids: &[usize]) -> Result<(), String> {
+        Column::write(
+            &index_path.join("article_ids.bin"),
+            article_ids.iter().map(|article_id| *article_id as u64),
+        )
+    }
+}
+
+// `width` values of every article, in the order of the article IDs
+pub struct ArticleStats<T> {
+    article_ids: Arc<ArticleIds>,
+    values: Column<T>,
+    width: usize,
+}
+
+impl<T: FixedWidth> ArticleStats<T> {
+    pub fn open(path: &Path, article_ids: &Arc<ArticleIds>, width: usize) -> Result<Self, String> {
+        Ok(ArticleStats {
+            article_ids: article_ids.clone(),
+            values: Column::open(path)?,
+            width,
+        })
+    }
+
+    pub fn get(&self, article_id: usize) -> Option<T> {
+        self.values
+            .get(self.article_ids.position(article_id)? * self.width)
+    }
+
+    pub fn row(&self, article_id: usize) -> Option<Vec<T>> {
+        let start = self.article_ids.position(article_id)? * self.width;
+        (start..start + self.width)
+            .map(|index| self.values.get(index))
+            .collect()
+    }
+}
+
+// Articles as JSON one after the other in articles.bin, in the order of the article IDs, with
+// where each one starts in article_offsets.bin followed by where the last one ends
+pub struct Docstore {
+    article_ids: Arc<ArticleIds>,
+    offsets: Column<u64>,
+    articles: Mmap,
+}
+
+impl Docstore {
+    pub fn open(index_path: &Path, article_ids: &Arc<ArticleIds>) -> Result<Self, String> {
+        Ok(Docstore {
+            article_ids: article_ids.clone(),
+            offsets: Column::open(&index_path.join("article_offsets.bin"))?,
+            articles: map_file(&index_path.join("articles.bin"))?,
+        })
+    }
+
+    pub fn get(&self, article_id: usize) -> Result<Article, String> {
+        let position = self
+            .article_ids
+            .position(article_id)
+            .ok_or(format!("Article {article_id} isn't in the index"))?;
+        let bytes = match (self.offsets.get(position), self.offsets.get(position + 1)) {
+            (Some(start), Some(end)) => self.articles.get(start as usize..end as usize),
+            _ => None,
+        }
+        .ok_or("Article offsets point past the end of articles.bin")?;
+        serde_json::from_slice(bytes).map_err(|e| format!("Failed to parse article: {e}"))
+    }
+
+    // Takes the JSON of every article, in the order of the article IDs
+    pub fn write(
+        index_path: &Path,
+        articles: impl IntoIterator<Item = Result<Vec<u8>, String>>,
+    ) -> Result<(), String> {
+        let mut writer = create_file(&index_path.join("articles.bin"))?;
+        let mut offsets = vec![0];
+        for article in articles {
+            let article = article?;
+            writer
+                .write_all(&article)
+                .map_err(|e| format!("Error writing to articles file: {e}"))?;
+            offsets.push(offsets[offsets.len() - 1] + article.len() as u64);
+        }
+        writer
+            .flush()
+            .map_err(|e| format!("Error writing to articles file: {e}"))?;
+        Column::write(&index_path.join("article_offsets.bin"), offsets)
+    }
+}
+
+// The postings lists of a field by token ID, one after the other in "<field>_postings.bin", with
+// where each one starts in "<field>_postings_offsets.bin" followed by where the last one ends.
+// Postings are grouped in blocks of BLOCK_SIZE article IDs, each of which can be decoded on its
+// own. A list starts with its document and collection frequencies and number of blocks, then the
+// header of every block as (block, first and last article ID, size of its articles, size of its
+// positions, block max frequency, field length and field norm). The blocks follow in the same
+// order, each as the (article ID gap, frequency) of its postings and then all of their position
+// gaps, so scoring can skip the positions. Article IDs are relative to the start of their block
+// and every number is a varint but the field norms, which are f64s.
+pub struct PostingsFile {
+    offsets: Column<u64>,
+    postings: Mmap,
+}
+
+// A postings list with its block headers read, and the blocks themselves left encoded
+pub struct PostingsList<'a> {
+    // Number of articles in the list and occurrences across all of them
+    pub doc_freq: usize,
+    pub collection_freq: usize,
+    // In increasing order
+    pub blocks: Vec<PostingsBlock<'a>>,
+}
+
+pub struct PostingsBlock<'a> {
+    pub block: usize,
+    pub first_article_id: usize,
+    pub last_article_id: usize,
+    pub block_max: BlockMax,
+    articles: &'a [u8],
+    positions: &'a [u8],
+}
+
+impl PostingsFile {
+    pub fn open(index_path: &Path, field: Field) -> Result<Self, String> {
+        Ok(PostingsFile {
+            offsets: Column::open(
+                &index_path.join(format!("{}_postings_offsets.bin", field.name())),
+            )?,
+            postings: map_file(&index_path.join(format!("{}_postings.bin", field.name())))?,
+        })
+    }
+
+    // Tokens without postings in the field have empty lists
+    pub fn list(&self, token_id: usize) -> Result<Option<PostingsList<'_>>, String> {
+        let (start, end) = match (self.offsets.get(token_id), self.offsets.get(token_id + 1)) {
+            (Some(start), Some(end)) if start < end => (start as usize, end as usize),
+            _ => return Ok(None),
+        };
+        let list = self
+            .postings
+            .get(start..end)
+            .ok_or("Postings offsets point past the end of the postings file")?;
+        let mut reader = VarintReader::new(list);
+        let doc_freq = reader.read()?;
+        let collection_freq = reader.read()?;
+        let num_blocks = reader.read()?;
+
+        let mut headers = Vec::new();
+        for _ in 0..num_blocks {
+            let block = reader.read()?;
+            let block_start = block
+                .checked_mul(BLOCK_SIZE)
+                .ok_or("Postings block is out of range")?;
+            headers.push((
+                block,
+                block_start + reader.read()?,
+                block_start + reader.read()?,
+                reader.read()?,
+                reader.read()?,
+                BlockMax {
+                    frequency: reader.read()?,
+                    field_length: reader.read()?,
+                    field_norm: reader.read_f64()?,
+                },
+            ));
+        }
+
+        let mut blocks = Vec::with_capacity(headers.len());
+        let mut articles_start = reader.position;
+        for (block, first_article_id, last_article_id, articles_size, positions_size, block_max) in
+            headers
+        {
+            let articles_end = articles_start.saturating_add(articles_size);
+            let positions_end = articles_end.saturating_add(positions_size);
+            if positions_end > list.len() {
+                return Err("Postings block runs past the end of its postings list".to_string());
+            }
+            blocks.push(PostingsBlock {
+                block,
+                first_article_id,
+                last_article_id,
+                block_max,
+                articles: &list[articles_start..articles_end],
+                positions: &list[articles_end..positions_end],
+            });
+            articles_start = positions_end;
+        }
+
+        Ok(Some(PostingsList {
+            doc_freq,
+            collection_freq,
+            blocks,
+        }))
+    }
+
+    // Takes the block maxes and postings of every token ID in order, with the postings sorted by
+    // article ID
+    pub fn write<'a>(
+        index_path: &Path,
+        field: Field,
+        lists: impl IntoIterator<Item = Result<(Option<&'a BlockMaxes>, Vec<(usize, Posting)>), String>>,
+    ) -> Result<(), String> {
+        let mut writer = create_file(&index_path.join(format!("{}_postings.bin", field.name())))?;
+        let mut offsets = vec![0];
+        for list in lists {
+            let (block_maxes, postings) = list?;
+            let encoded = encode_postings_list(block_maxes, &postings)?;
+            writer
+                .write_all(&encoded)
+                .map_err(|e| format!("Error writing to postings file: {e}"))?;
+            offsets.push(offsets[offsets.len() - 1] + encoded.len() as u64);
+        }
+        writer
+            .flush()
+            .map_err(|e| format!("Error writing to postings file: {e}"))?;
+        Column::write(
+            &index_path.join(format!("{}_postings_offsets.bin", field.name())),
+            offsets,
+        )
+    }
+}
+
+impl PostingsList<'_> {
+    pub fn block_maxes(&self) -> BlockMaxes {
+        self.blocks
+            .iter()
+            .map(|block| (block.block, block.block_max))
+            .collect()
+    }
+
+    // Decodes every block, without the positions
+    pub fn article_ids(&self) -> Result<Vec<usize>, String> {
+        let mut article_ids = Vec::with_capacity(self.doc_freq);
+        for block in &self.blocks {
+            article_ids.extend(
+                block
+                    .articles()?
+                    .into_iter()
+                    .map(|(article_id, _)| article_id),
+            );
+        }
+        Ok(article_ids)
+    }
+}
+
+impl PostingsBlock<'_> {
+    // Article IDs in the block along with their frequencies
+    pub fn articles(&self) -> Result<Vec<(usize, usize)>, String> {
+        let mut reader = VarintReader::new(self.articles);
+        let mut articles = Vec::new();
+        let mut article_id = self.block * BLOCK_SIZE;
+        while !reader.is_done() {
+            article_id += reader.read()?;
+            articles.push((article_id, reader.read()?));
+        }
+        Ok(articles)
+    }
+
+    // Positions of every article in the block, given the articles and frequencies from `articles`
+    pub fn positions(&self, articles: &[(usize, usize)]) -> Result<Vec<Vec<usize>>, String> {
+        let mut reader = VarintReader::new(self.positions);
+        articles
+            .iter()
+            .map(|(_, frequency)| {
+                let mut positions = Vec::with_capacity((*frequency).min(self.positions.len()));
+                let mut position = 0;
+                for _ in 0..*frequency {
+                    position += reader.read()?;
+                    positions.push(position);
+                }
+                Ok(positions)
+            })
+            .collect()
+    }
+}
+
+// Lists without postings are left empty, there's nothing to search in them
+fn encode_postings_list(
+    block_maxes: Option<&BlockMaxes>,
+    postings: &[(usize, Posting)],
+) -> Result<Vec<u8>, String> {
+    let mut encoded = Vec::new();
+    if postings.is_empty() {
+        return Ok(encoded);
+    }
+
+    let mut headers = Vec::new();
+    let mut blocks = Vec::new();
+    let mut num_blocks = 0;
+    for block_postings in postings.chunk_by(|(article_id, _), (next_article_id, _)| {
+        block_index(*article_id) == block_index(*next_article_id)
+    }) {
+        let block = block_index(block_postings[0].0);
+        let block_start = block * BLOCK_SIZE;
+        let block_max = block_maxes
+            .and_then(|block_maxes| block_maxes.get(&block))
+            .ok_or(format!("Postings block {block} has no block max"))?;
+
+        let mut articles = Vec::new();
+        let mut positions = Vec::new();
+        let mut prev_article_id = block_start;
+        for (article_id, posting) in block_postings {
+            write_varint(&mut articles, article_id - prev_article_id);
+            prev_article_id = *article_id;
+            write_varint(&mut articles, posting.frequency);
+            let mut prev_position = 0;
+            for position in &posting.positions {
+                write_varint(&mut positions, position - prev_position);
+                prev_position = *position;
+            }
+        }
+
+        write_varint(&mut headers, block);
+        write_varint(&mut headers, block_postings[0].0 - block_start);
+        write_varint(&mut headers, prev_article_id - block_start);
+        write_varint(&mut headers, articles.len());
+        write_varint(&mut headers, positions.len());
+        write_varint(&mut headers, block_max.frequency);
+        write_varint(&mut headers, block_max.field_length);
+        headers.extend(block_max.field_norm.to_le_bytes());
+        blocks.extend(articles);
+        blocks.extend(positions);
+        num_blocks += 1;
+    }
+
+    write_varint(&mut encoded, postings.len());
+    write_varint(
+        &mut encoded,
+        postings.iter().map(|(_, posting)| posting.frequency).sum(),
+    );
+    write_varint(&mut encoded, num_blocks);
+    encoded.extend(headers);
+    encoded.extend(blocks);
+    Ok(encoded)
+}
+
+// Seven bits at a time, lowest first, with the high bit set on every byte but the last
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct VarintReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> VarintReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        VarintReader { bytes, position: 0 }
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn read(&mut self) -> Result<usize, String> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *self
+                .bytes
+                .get(self.position)
+                .ok_or("Varint runs past the end of its postings list")?;
+            self.position += 1;
+            if shift >= usize::BITS {
+                return Err("Varint is too long".to_string());
+            }
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_f64(&mut self) -> Result<f64, String> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + 8)
+            .ok_or("Field norm runs past the end of its postings list")?;
+        self.position += 8;
+        Ok(f64::from_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip() {
+        let values = [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16383,
+            16384,
+            u32::MAX as usize,
+            usize::MAX,
+        ];
+        let mut bytes = Vec::new();
+        for value in values {
+            write_varint(&mut bytes, value);
+        }
+        let mut reader = VarintReader::new(&bytes);
+        for value in values {
+            assert_eq!(reader.read(), Ok(value));
+        }
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn truncated_varints_are_errors() {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 16384);
+        bytes.pop();
+        assert!(VarintReader::new(&bytes).read().is_err());
+        assert!(VarintReader::new(&[0xff; 11]).read().is_err());
+    }
+
+    // Article ID -> posting, decoding every block
+    fn decode(list: &PostingsList) -> Vec<(usize, Posting)> {
+        let mut postings = Vec::new();
+        for block in &list.blocks {
+            let articles = block.articles().unwrap();
+            let positions = block.positions(&articles).unwrap();
+            for ((article_id, frequency), positions) in articles.into_iter().zip(positions) {
+                assert_eq!(block_index(article_id), block.block);
+                postings.push((
+                    article_id,
+                    Posting {
+                        frequency,
+                        positions,
+                    },
+                ));
+            }
+        }
+        postings
+    }
+
+    #[test]
+    fn postings_lists_round_trip() {
+        let index_path =
+            std::env::temp_dir().join(format!("wiki-search-postings-{}", std::process::id()));
+        std::fs::create_dir_all(&index_path).unwrap();
+
+        let posting = |positions: Vec<usize>| Posting {
+            frequency: positions.len(),
+            positions,
+        };
+        let postings = vec![
+            (3, posting(vec![0, 5, 200])),
+            (900, posting(vec![2])),
+            (1500, posting(vec![7])),
+            (70000, posting(vec![128, 129, 100000])),
+        ];
+        let block_max = |frequency, field_length, field_norm| BlockMax {
+            frequency,
+            field_length,
+            field_norm,
+        };
+        let block_maxes = BlockMaxes::from([
+            (0, block_max(3, 250, 1.0986122886681098)),
+            (1, block_max(1, 20, 0.5)),
+            (68, block_max(3, 100001, 0.1)),
+        ]);
+        let single_block_maxes = BlockMaxes::from([(0, block_max(1, 2, 1.0))]);
+        // The second token has no postings in the field
+        let lists = vec![
+            Ok((Some(&block_maxes), postings.clone())),
+            Ok((None, Vec::new())),
+            Ok((Some(&single_block_maxes), vec![(0, posting(vec![1]))])),
+        ];
+        PostingsFile::write(&index_path, Field::Text, lists).unwrap();
+        let postings_file = PostingsFile::open(&index_path, Field::Text).unwrap();
+
+        let list = postings_file.list(0).unwrap().unwrap();
+        assert_eq!(list.doc_freq, 4);
+        assert_eq!(list.collection_freq, 8);
+        assert_eq!(list.block_maxes(), block_maxes);
+        assert_eq!(
+            list.blocks
+                .iter()
+                .map(|block| (block.first_article_id, block.last_article_id))
+                .collect::<Vec<(usize, usize)>>(),
+            vec![(3, 900), (1500, 1500), (70000, 70000)]
+        );
+        assert_eq!(list.article_ids().unwrap(), vec![3, 900, 1500, 70000]);
+        assert_eq!(decode(&list), postings);
+        // Blocks decode on their own
+        assert_eq!(list.blocks[2].articles().unwrap(), vec![(70000, 3)]);
+
+        assert!(postings_file.list(1).unwrap().is_none());
+        let list = postings_file.list(2).unwrap().unwrap();
+        assert_eq!(list.block_maxes(), single_block_maxes);
+        assert_eq!(decode(&list), vec![(0, posting(vec![1]))]);
+        assert!(postings_file.list(3).unwrap().is_none());
+
+        std::fs::remove_dir_all(&index_path).unwrap();
+    }
+
+    #[test]
+    fn postings_need_the_block_max_of_their_block() {
+        let index_path = std::env::temp_dir().join(format!(
+            "wiki-search-postings-block-maxes-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&index_path).unwrap();
+
+        let block_maxes = BlockMaxes::from([(
+            0,
+            BlockMax {
+                frequency: 1,
+                field_length: 1,
+                field_norm: 1.0,
+            },
+        )]);
+        let postings = vec![(
+            BLOCK_SIZE,
+            Posting {
+                frequency: 1,
+                positions: vec![0],
+            },
+        )];
+        assert!(PostingsFile::write(
+            &index_path,
+            Field::Text,
+            vec![Ok((Some(&block_maxes), postings))]
+        )
+        .is_err());
+
+        std::fs::remove_dir_all(&index_path).unwrap();
+    }
+}
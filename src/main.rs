@@ -1,10 +1,20 @@
 mod common;
 mod index_engine;
-mod query;
+mod index_files;
+mod query_engine;
 
 use clap::Parser;
 
+use common::{Page, QueryResults, RankingParams, PROXIMITY_WEIGHT, RERANK_CANDIDATES};
 use index_engine::index_engine::build_index;
+use query_engine::{
+    feedback::FeedbackModel,
+    index_reader::{IndexReader, SearchOptions},
+    ltr::{export_features, load_qrels, load_queries, Reranker},
+    query_parser::{parse_query, ParseError},
+    scoring::RankingModel,
+    synonyms::SynonymMap,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,8 +27,62 @@ struct Args {
     wiki_dump_path: Option<String>,
     #[arg(short, long)]
     index_path: String,
-    #[arg(short, long, default_value_t = 10)]
+    // Trains an LSA model with this many dimensions when building the index
+    #[arg(long)]
+    lsa_dims: Option<usize>,
+    #[arg(short, long, alias = "limit", default_value_t = 10)]
     num_max_results: usize,
+    // Number of results to skip, for the pages after the first
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+    #[arg(long)]
+    synonyms_path: Option<String>,
+    #[arg(long)]
+    explain: bool,
+    #[arg(long, default_value_t = PROXIMITY_WEIGHT)]
+    proximity_weight: f64,
+    #[arg(long)]
+    auto_correct: bool,
+    #[arg(long, default_value = "bm25f")]
+    ranking_model: String,
+    // Ranking parameters override the ones in the config file, which override the index defaults
+    #[arg(long)]
+    config_path: Option<String>,
+    #[arg(long)]
+    k1: Option<f64>,
+    #[arg(long)]
+    b: Option<f64>,
+    #[arg(long)]
+    k2: Option<f64>,
+    #[arg(long)]
+    snippet_offset: Option<usize>,
+    // Pseudo-relevance feedback: expands the query with terms from the top articles and reruns it
+    #[arg(long)]
+    feedback: Option<String>,
+    #[arg(long)]
+    feedback_docs: Option<usize>,
+    #[arg(long)]
+    feedback_terms: Option<usize>,
+    #[arg(long)]
+    feedback_weight: Option<f64>,
+    // Blends the ranking model's score with the LSA similarity of the article to the query
+    #[arg(long)]
+    semantic_weight: Option<f64>,
+    // Shows near-duplicate articles as separate results
+    #[arg(long)]
+    no_collapse: bool,
+    // Learning to rank: writes the features of each query's results along with their judgments
+    #[arg(long)]
+    export_features: Option<String>,
+    #[arg(long)]
+    qrels_path: Option<String>,
+    #[arg(long, default_value = "features.txt")]
+    features_path: String,
+    // Reranks the top candidates of the ranking model with a trained model
+    #[arg(long)]
+    rerank_model: Option<String>,
+    #[arg(long, default_value_t = RERANK_CANDIDATES)]
+    num_candidates: usize,
 }
 
 #[tokio::main]
@@ -26,7 +90,7 @@ async fn main() {
     let args = Args::parse();
 
     if args.build_index {
-        let wiki_dump_path = args.wiki_dump_path;
+        let wiki_dump_path = args.wiki_dump_path.clone();
         if wiki_dump_path.is_none() {
             println!("wiki-dump-path is required to build index");
             return;
@@ -35,7 +99,7 @@ async fn main() {
 
         let index_path = args.index_path.clone();
 
-        match build_index(&wiki_dump_path, &index_path).await {
+        match build_index(&wiki_dump_path, &index_path, args.lsa_dims).await {
             Ok(num_articles) => {
                 println!("Index built with {} articles", num_articles);
             }
@@ -45,15 +109,220 @@ async fn main() {
         }
     }
 
-    if args.search.is_some() {
-        let query = args.search.unwrap();
-        let index_path = args.index_path.clone();
-        let num_max_results = args.num_max_results;
+    if let Some(queries_path) = &args.export_features {
+        let (ranking_model, ranking_params, synonyms) = match load_search_settings(&args) {
+            Ok(settings) => settings,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+        let queries = match load_queries(queries_path) {
+            Ok(queries) => queries,
+            Err(err) => {
+                println!("Error loading queries: {}", err);
+                return;
+            }
+        };
+        let reader = match IndexReader::open(&args.index_path) {
+            Ok(reader) => reader,
+            Err(err) => {
+                println!("Error opening index: {}", err);
+                return;
+            }
+        };
+        let searcher = reader.searcher(synonyms);
+        let qrels = match &args.qrels_path {
+            Some(qrels_path) => match load_qrels(qrels_path) {
+                Ok(qrels) => qrels,
+                Err(err) => {
+                    println!("Error loading qrels: {}", err);
+                    return;
+                }
+            },
+            None => {
+                println!("qrels-path is required to export features");
+                return;
+            }
+        };
+        // Features are exported for the candidates a reranker would see, near-duplicates included
+        // so that none of the judged articles are left out of the training data
+        let options = SearchOptions {
+            page: Page {
+                offset: 0,
+                limit: args.num_candidates,
+            },
+            ranking_model,
+            ranking_params: RankingParams {
+                collapse_duplicates: Some(false),
+                ..ranking_params
+            },
+            proximity_weight: args.proximity_weight,
+        };
+        let search = |query: &str| searcher.search(query, &options);
+        match export_features(&queries, &qrels, &args.features_path, search) {
+            Ok(num_lines) => println!(
+                "Exported features of {} results for {} queries to {}",
+                num_lines,
+                queries.len(),
+                args.features_path
+            ),
+            Err(err) => println!("Error exporting features: {}", err),
+        }
+    }
+
+    if let Some(query) = args.search.clone() {
+        let page = Page {
+            offset: args.offset,
+            limit: args.num_max_results,
+        };
 
-        match query::get_query_results(&query, num_max_results, &index_path) {
+        if let Err(err) = parse_query(&query) {
+            print_parse_error(&query, &err);
+            return;
+        }
+
+        let (ranking_model, ranking_params, synonyms) = match load_search_settings(&args) {
+            Ok(settings) => settings,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+        let reader = match IndexReader::open(&args.index_path) {
+            Ok(reader) => reader,
+            Err(err) => {
+                println!("Error opening index: {}", err);
+                return;
+            }
+        };
+        let searcher = reader.searcher(synonyms);
+        let reranker = match &args.rerank_model {
+            Some(rerank_model) => match Reranker::load(rerank_model) {
+                Ok(reranker) => Some(reranker),
+                Err(err) => {
+                    println!("Error loading reranking model: {}", err);
+                    return;
+                }
+            },
+            None => None,
+        };
+        // The reranker picks the page out of more candidates
+        let candidates = match reranker {
+            Some(_) => Page {
+                offset: 0,
+                limit: args
+                    .num_candidates
+                    .max(page.offset.saturating_add(page.limit)),
+            },
+            None => page,
+        };
+        let options = SearchOptions {
+            page,
+            ranking_model,
+            ranking_params,
+            proximity_weight: args.proximity_weight,
+        };
+        let candidate_options = SearchOptions {
+            page: candidates,
+            ..options.clone()
+        };
+        let search = |query: &str| {
+            let mut query_results = searcher.search(query, &candidate_options)?;
+            if let Some(reranker) = &reranker {
+                query_results.results = reranker.rerank(query_results.results, page);
+            }
+            Ok::<QueryResults, String>(query_results)
+        };
+
+        if args.explain {
+            let expanded_terms = match searcher.expand_query(&query) {
+                Ok(expanded_terms) => expanded_terms,
+                Err(err) => {
+                    println!("Error parsing query: {}", err);
+                    return;
+                }
+            };
+            println!("Query expansion for \"{}\":", query);
+            for (expanded_term, field) in expanded_terms {
+                let field_prefix = match field {
+                    Some(field) => format!("{}:", field.name()),
+                    None => String::new(),
+                };
+                let alternatives = expanded_term
+                    .alternatives
+                    .iter()
+                    .map(|alternative| alternative.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" OR ");
+                println!(
+                    "  {}{} => {}",
+                    field_prefix,
+                    expanded_term.tokens.join(" "),
+                    alternatives
+                );
+            }
+            println!();
+        }
+
+        let mut query = query;
+        let mut query_results = search(&query);
+
+        // Runs the corrected query instead when auto-correct is on
+        if let Ok(QueryResults {
+            suggestion: Some(suggestion),
+            ..
+        }) = &query_results
+        {
+            if args.auto_correct {
+                println!(
+                    "Showing results for \"{}\" instead of \"{}\"\n",
+                    suggestion, query
+                );
+                query = suggestion.clone();
+                query_results = search(&query);
+            } else {
+                println!("Did you mean: {}?\n", suggestion);
+            }
+        }
+
+        match query_results {
             Ok(query_results) => {
                 println!("Query results for \"{}\":\n", query);
-                for query_result in query_results {
+                let total_hits = if query_results.exact_total_hits {
+                    query_results.total_hits.to_string()
+                } else {
+                    format!("at least {}", query_results.total_hits)
+                };
+                if !query_results.results.is_empty() {
+                    println!(
+                        "Showing results {} to {} of {} matching articles\n",
+                        page.offset + 1,
+                        page.offset + query_results.results.len(),
+                        total_hits
+                    );
+                } else if page.offset > 0 {
+                    println!(
+                        "No more results, the query matches {} articles\n",
+                        total_hits
+                    );
+                }
+                // Explains the ranking model's scores, before any reranking
+                let explanations = if args.explain {
+                    let article_ids = query_results
+                        .results
+                        .iter()
+                        .map(|query_result| query_result.article_id)
+                        .collect::<Vec<usize>>();
+                    match searcher.explain_many(&query, &article_ids, &options) {
+                        Ok(explanations) => explanations.into_iter().map(Ok).collect(),
+                        Err(err) => vec![Err(err); article_ids.len()],
+                    }
+                } else {
+                    Vec::new()
+                };
+                let mut explanations = explanations.into_iter();
+                for query_result in query_results.results {
                     println!(
                         "Title: {}\nArticle ID: {}\nScore: {}\nSnippet: {}\nLink: https://en.wikipedia.org/?curid={}\n",
                         query_result.title,
@@ -62,6 +331,14 @@ async fn main() {
                         query_result.snippet,
                         query_result.article_id,
                     );
+                    if query_result.duplicates > 0 {
+                        println!("Duplicates: {}\n", query_result.duplicates);
+                    }
+                    match explanations.next() {
+                        Some(Ok(explanation)) => println!("Explanation:\n{}", explanation),
+                        Some(Err(err)) => println!("Error explaining score: {}\n", err),
+                        None => {}
+                    }
                 }
             }
             Err(err) => {
@@ -70,3 +347,72 @@ async fn main() {
         }
     }
 }
+
+// The ranking model, ranking parameters and synonyms from the command line and the files it points to
+fn load_search_settings(args: &Args) -> Result<(RankingModel, RankingParams, SynonymMap), String> {
+    let ranking_model = match RankingModel::from_name(&args.ranking_model) {
+        Some(ranking_model) => ranking_model,
+        None => {
+            let ranking_models = RankingModel::ALL
+                .iter()
+                .map(|ranking_model| ranking_model.name())
+                .collect::<Vec<&str>>()
+                .join(", ");
+            return Err(format!(
+                "Unknown ranking model \"{}\", expected one of {}",
+                args.ranking_model, ranking_models
+            ));
+        }
+    };
+
+    let feedback = match &args.feedback {
+        Some(feedback) => match FeedbackModel::from_name(feedback) {
+            Some(feedback_model) => Some(feedback_model),
+            None => {
+                let feedback_models = FeedbackModel::ALL
+                    .iter()
+                    .map(|feedback_model| feedback_model.name())
+                    .collect::<Vec<&str>>()
+                    .join(", ");
+                return Err(format!(
+                    "Unknown feedback model \"{}\", expected one of {}",
+                    feedback, feedback_models
+                ));
+            }
+        },
+        None => None,
+    };
+
+    let config_params = match &args.config_path {
+        Some(config_path) => RankingParams::load(config_path)
+            .map_err(|err| format!("Error loading config: {}", err))?,
+        None => RankingParams::default(),
+    };
+    let ranking_params = config_params.merge(&RankingParams {
+        k1: args.k1,
+        b: args.b,
+        k2: args.k2,
+        snippet_offset: args.snippet_offset,
+        feedback,
+        feedback_docs: args.feedback_docs,
+        feedback_terms: args.feedback_terms,
+        feedback_weight: args.feedback_weight,
+        semantic_weight: args.semantic_weight,
+        collapse_duplicates: args.no_collapse.then_some(false),
+    });
+
+    let synonyms = match &args.synonyms_path {
+        Some(synonyms_path) => SynonymMap::load(synonyms_path)
+            .map_err(|err| format!("Error loading synonyms: {}", err))?,
+        None => SynonymMap::new(),
+    };
+
+    Ok((ranking_model, ranking_params, synonyms))
+}
+
+// Shows the query with a caret under the character the error points at
+fn print_parse_error(query: &str, err: &ParseError) {
+    println!("Error parsing query: {}", err.message);
+    println!("  {}", query);
+    println!("  {}^", " ".repeat(err.position));
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::term_dictionary::TermDictionary;
+use crate::common::{
+    RankingParams, FEEDBACK_DOCS, FEEDBACK_MAX_DOC_FREQ, FEEDBACK_TERMS, FEEDBACK_WEIGHT,
+};
+
+// How expansion terms are picked from the top articles of the first pass
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedbackModel {
+    Rm3,
+    Rocchio,
+}
+
+impl FeedbackModel {
+    pub const ALL: [FeedbackModel; 2] = [FeedbackModel::Rm3, FeedbackModel::Rocchio];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FeedbackModel::Rm3 => "rm3",
+            FeedbackModel::Rocchio => "rocchio",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FeedbackModel> {
+        FeedbackModel::ALL
+            .into_iter()
+            .find(|feedback_model| feedback_model.name() == name)
+    }
+}
+
+// Pseudo-relevance feedback settings of a query
+pub struct Feedback {
+    pub model: FeedbackModel,
+    // Number of top articles the expansion terms are taken from
+    pub num_docs: usize,
+    pub num_terms: usize,
+    // Weight of the original query, the expansion terms get the rest
+    pub original_weight: f64,
+}
+
+impl Feedback {
+    // None when feedback isn't turned on
+    pub fn from_params(params: &RankingParams) -> Option<Feedback> {
+        Some(Feedback {
+            model: params.feedback?,
+            num_docs: params.feedback_docs.unwrap_or(FEEDBACK_DOCS),
+            num_terms: params.feedback_terms.unwrap_or(FEEDBACK_TERMS),
+            original_weight: params
+                .feedback_weight
+                .unwrap_or(FEEDBACK_WEIGHT)
+                .clamp(0.0, 1.0),
+        })
+    }
+
+    // The best expansion terms from the tokens of the top articles and their first pass scores,
+    // with weights adding up to 1. Query tokens and tokens in too many articles to tell them apart
+    // (mostly stop words) are skipped.
+    pub fn expansion_terms(
+        &self,
+        documents: &[(Vec<String>, f64)],
+        query_tokens: &HashSet<&str>,
+        term_dictionary: &TermDictionary,
+        num_articles: usize,
+    ) -> Vec<(String, f64)> {
+        let max_doc_freq = FEEDBACK_MAX_DOC_FREQ * num_articles as f64;
+        let mut doc_freqs = HashMap::new();
+        let mut term_weights: HashMap<&str, f64> = HashMap::new();
+
+        // Relevance of each article for RM3, P(Q|D) normalized over the top articles. Scores are
+        // treated as log likelihoods so every ranking model gives positive weights.
+        let max_score = documents
+            .iter()
+            .map(|(_, score)| *score)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let relevances = documents
+            .iter()
+            .map(|(_, score)| (score - max_score).exp())
+            .collect::<Vec<f64>>();
+        let total_relevance = relevances.iter().sum::<f64>();
+
+        for ((tokens, _), relevance) in documents.iter().zip(relevances) {
+            let mut term_freqs: HashMap<&str, f64> = HashMap::new();
+            for token in tokens {
+                *term_freqs.entry(token.as_str()).or_insert(0.0) += 1.0;
+            }
+            term_freqs.retain(|token, _| {
+                if query_tokens.contains(token) {
+                    return false;
+                }
+                let doc_freq = *doc_freqs.entry(*token).or_insert_with(|| {
+                    term_dictionary
+                        .get(token)
+                        .map(|token_id| term_dictionary.doc_freq(token_id))
+                        .unwrap_or(0) as f64
+                });
+                doc_freq > 0.0 && doc_freq <= max_doc_freq
+            });
+
+            match self.model {
+                // P(w|R) = sum over the articles of P(w|D) P(Q|D)
+                FeedbackModel::Rm3 => {
+                    let length = tokens.len().max(1) as f64;
+                    for (token, term_freq) in term_freqs {
+                        *term_weights.entry(token).or_insert(0.0) +=
+                            term_freq / length * relevance / total_relevance;
+                    }
+                }
+                // Centroid of the articles' length normalized TF-IDF vectors
+                FeedbackModel::Rocchio => {
+                    let weights = term_freqs
+                        .into_iter()
+                        .map(|(token, term_freq)| {
+                            let idf = (num_articles as f64 / doc_freqs[token]).ln();
+                            (token, (1.0 + term_freq.ln()) * idf)
+                        })
+                        .collect::<Vec<(&str, f64)>>();
+                    let norm = weights
+                        .iter()
+                        .map(|(_, weight)| weight * weight)
+                        .sum::<f64>()
+                        .sqrt();
+                    if norm == 0.0 {
+                        continue;
+                    }
+                    for (token, weight) in weights {
+                        *term_weights.entry(token).or_insert(0.0) +=
+                            weight / norm / documents.len() as f64;
+                    }
+                }
+            }
+        }
+
+        let mut expansion_terms = term_weights
+            .into_iter()
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect::<Vec<(&str, f64)>>();
+        expansion_terms.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+        expansion_terms.truncate(self.num_terms);
+
+        let total_weight = expansion_terms
+            .iter()
+            .map(|(_, weight)| weight)
+            .sum::<f64>();
+        expansion_terms
+            .into_iter()
+            .map(|(token, weight)| (token.to_string(), weight / total_weight))
+            .collect()
+    }
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use super::{
+    query_engine::{expand_query, explain_query, get_query_results},
+    scoring::{Explanation, RankingModel},
+    synonyms::{ExpandedTerm, SynonymMap},
+    term_dictionary::TermDictionary,
+};
+use crate::{
+    common::{Field, IndexConfig, LsaModel, Page, QueryResults, RankingParams, MINHASH_SIZE},
+    index_files::{ArticleIds, ArticleStats, Docstore, PostingsFile},
+};
+
+// An index opened for searching, once for any number of queries. The term dictionary, postings,
+// per-article stats and articles are memory-mapped rather than loaded, so opening an index is
+// quick whatever its size and only the pages queries touch take up memory.
+pub struct IndexReader {
+    pub term_dictionary: TermDictionary,
+    pub field_stats: HashMap<Field, FieldStats>,
+    pub postings: HashMap<Field, PostingsFile>,
+    pub docstore: Docstore,
+    pub index_config: IndexConfig,
+    pub num_articles: usize,
+    // Number of links pointing to each article from other articles
+    pub inlinks: ArticleStats<u32>,
+    // MinHash signature of each article's text, all zeros if it's too short for one
+    pub minhashes: ArticleStats<u64>,
+    // None if the index was built without one
+    pub lsa: Option<LsaIndex>,
+}
+
+// The token vectors of the LSA model are loaded whole, since there are at most LSA_MAX_TERMS of
+// them, while the article vectors are memory-mapped like the other per-article files
+pub struct LsaIndex {
+    pub model: LsaModel,
+    pub article_vectors: ArticleStats<f32>,
+}
+
+pub struct FieldStats {
+    // Number of tokens in the field
+    lengths: ArticleStats<u32>,
+    // Length of the log-scaled term frequency vector of the field
+    norms: ArticleStats<f64>,
+    pub average_length: f64,
+}
+
+impl FieldStats {
+    pub fn length(&self, article_id: usize) -> usize {
+        self.lengths.get(article_id).unwrap_or(0) as usize
+    }
+
+    pub fn norm(&self, article_id: usize) -> f64 {
+        self.norms.get(article_id).unwrap_or(0.0)
+    }
+}
+
+impl IndexReader {
+    pub fn open(index_path: &str) -> Result<Self, String> {
+        let index_path = Path::new(index_path);
+        let article_ids = Arc::new(ArticleIds::open(index_path)?);
+        let average_lengths = read_average_lengths(index_path)?;
+        let mut field_stats = HashMap::new();
+        let mut postings = HashMap::new();
+        for field in Field::ALL {
+            field_stats.insert(
+                field,
+                FieldStats {
+                    lengths: ArticleStats::open(
+                        &index_path.join(format!("{}_lengths.bin", field.name())),
+                        &article_ids,
+                        1,
+                    )?,
+                    norms: ArticleStats::open(
+                        &index_path.join(format!("{}_norms.bin", field.name())),
+                        &article_ids,
+                        1,
+                    )?,
+                    average_length: average_lengths.get(&field).copied().unwrap_or(0.0),
+                },
+            );
+            postings.insert(field, PostingsFile::open(index_path, field)?);
+        }
+        let lsa = if index_path.join("lsa.bin").exists() {
+            let model = LsaModel::load(index_path)?;
+            let article_vectors = ArticleStats::open(
+                &index_path.join("lsa_vectors.bin"),
+                &article_ids,
+                model.dims,
+            )?;
+            Some(LsaIndex {
+                model,
+                article_vectors,
+            })
+        } else {
+            None
+        };
+
+        Ok(IndexReader {
+            term_dictionary: TermDictionary::load(index_path)?,
+            field_stats,
+            postings,
+            docstore: Docstore::open(index_path, &article_ids)?,
+            index_config: IndexConfig::load(index_path)?,
+            num_articles: article_ids.num_articles(),
+            inlinks: ArticleStats::open(&index_path.join("inlinks.bin"), &article_ids, 1)?,
+            minhashes: ArticleStats::open(
+                &index_path.join("minhashes.bin"),
+                &article_ids,
+                MINHASH_SIZE,
+            )?,
+            lsa,
+        })
+    }
+
+    pub fn searcher(&self, synonyms: SynonymMap) -> Searcher<'_> {
+        Searcher {
+            reader: self,
+            synonyms,
+        }
+    }
+}
+
+// How a query is ranked and which page of its results is returned
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub page: Page,
+    pub ranking_model: RankingModel,
+    pub ranking_params: RankingParams,
+    pub proximity_weight: f64,
+}
+
+// Runs queries against an open index, expanding them with the same synonyms
+pub struct Searcher<'a> {
+    reader: &'a IndexReader,
+    synonyms: SynonymMap,
+}
+
+impl Searcher<'_> {
+    pub fn search(&self, query: &str, options: &SearchOptions) -> Result<QueryResults, String> {
+        get_query_results(query, self.reader, &self.synonyms, options)
+    }
+
+    // How the articles' scores for the query were computed, in the order of `article_ids`
+    pub fn explain_many(
+        &self,
+        query: &str,
+        article_ids: &[usize],
+        options: &SearchOptions,
+    ) -> Result<Vec<Explanation>, String> {
+        explain_query(query, article_ids, self.reader, &self.synonyms, options)
+    }
+
+    pub fn expand_query(&self, query: &str) -> Result<Vec<(ExpandedTerm, Option<Field>)>, String> {
+        expand_query(query, &self.reader.term_dictionary, &self.synonyms)
+    }
+}
+
+fn read_average_lengths(index_path: &Path) -> Result<HashMap<Field, f64>, String> {
+    let average_lengths_file = std::fs::File::open(index_path.join("average_lengths.bin"))
+        .map_err(|e| format!("Failed to open average_lengths.bin: {e}"))?;
+    bincode::deserialize_from(average_lengths_file)
+        .map_err(|e| format!("Failed to parse average_lengths.bin: {e}"))
+}
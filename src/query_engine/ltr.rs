@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Write},
+};
+
+use serde::Deserialize;
+
+use crate::common::{Page, QueryResult, QueryResults};
+
+// Names of the features in QueryResult::features, in order. Feature i is written as i + 1 in
+// the SVMlight format.
+pub const FEATURE_NAMES: [&str; 10] = [
+    "bm25_title",
+    "bm25_text",
+    "bm25_category",
+    "bm25_anchor",
+    "term_coverage",
+    "proximity",
+    "log_text_length",
+    "log_inlinks",
+    "title_match",
+    "score",
+];
+
+// Queries to export features for, one "<query id>\t<query>" per line
+pub fn load_queries(queries_path: &str) -> Result<Vec<(String, String)>, String> {
+    let contents = std::fs::read_to_string(queries_path)
+        .map_err(|e| format!("Failed to read queries file: {e}"))?;
+    let mut queries = Vec::new();
+    for (line_num, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.split_once('\t') {
+            Some((query_id, query)) => {
+                queries.push((query_id.trim().to_string(), query.trim().to_string()))
+            }
+            None => {
+                return Err(format!(
+                    "Invalid query on line {}: expected \"<query id>\\t<query>\"",
+                    line_num + 1
+                ))
+            }
+        }
+    }
+    Ok(queries)
+}
+
+// Relevance judgments in the TREC format, "<query id> <iteration> <article id> <relevance>" per
+// line. Returns query ID -> article ID -> relevance.
+pub fn load_qrels(qrels_path: &str) -> Result<HashMap<String, HashMap<usize, u32>>, String> {
+    let contents = std::fs::read_to_string(qrels_path)
+        .map_err(|e| format!("Failed to read qrels file: {e}"))?;
+    let mut qrels: HashMap<String, HashMap<usize, u32>> = HashMap::new();
+    for (line_num, line) in contents.lines().enumerate() {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let (query_id, article_id, relevance) = match fields.as_slice() {
+            [] => continue,
+            [query_id, _, article_id, relevance] => (query_id, article_id, relevance),
+            _ => {
+                return Err(format!(
+                    "Invalid judgment on line {}: expected 4 fields",
+                    line_num + 1
+                ))
+            }
+        };
+        let article_id = article_id
+            .parse::<usize>()
+            .map_err(|e| format!("Invalid article ID on line {}: {e}", line_num + 1))?;
+        let relevance = relevance
+            .parse::<u32>()
+            .map_err(|e| format!("Invalid relevance on line {}: {e}", line_num + 1))?;
+        qrels
+            .entry(query_id.to_string())
+            .or_default()
+            .insert(article_id, relevance);
+    }
+    Ok(qrels)
+}
+
+// Writes the features of every result of every query in the SVMlight (LETOR) format:
+//   <relevance> qid:<query id> 1:<feature> 2:<feature> ... # <article id>
+// Results without a judgment are considered not relevant. Returns the number of lines written.
+pub fn export_features(
+    queries: &[(String, String)],
+    qrels: &HashMap<String, HashMap<usize, u32>>,
+    features_path: &str,
+    search: impl Fn(&str) -> Result<QueryResults, String>,
+) -> Result<usize, String> {
+    let features_file = std::fs::File::create(features_path)
+        .map_err(|e| format!("Failed to create features file: {e}"))?;
+    let mut writer = BufWriter::new(features_file);
+    let mut num_lines = 0;
+
+    for (query_id, query) in queries {
+        let query_results =
+            search(query).map_err(|e| format!("Failed to search for query {query_id}: {e}"))?;
+        let judgments = qrels.get(query_id);
+        for query_result in query_results.results {
+            let relevance = judgments
+                .and_then(|judgments| judgments.get(&query_result.article_id))
+                .copied()
+                .unwrap_or(0);
+            let features = query_result
+                .features
+                .iter()
+                .enumerate()
+                .map(|(i, feature)| format!("{}:{}", i + 1, feature))
+                .collect::<Vec<String>>()
+                .join(" ");
+            writeln!(
+                writer,
+                "{} qid:{} {} # {}",
+                relevance, query_id, features, query_result.article_id
+            )
+            .map_err(|e| format!("Failed to write features: {e}"))?;
+            num_lines += 1;
+        }
+    }
+
+    writer
+        .flush()
+        .map_err(|e| format!("Failed to write features: {e}"))?;
+    Ok(num_lines)
+}
+
+// A trained model scoring results by their features, stored as JSON:
+//   {"type": "linear", "weights": [...], "bias": 0.0}
+//   {"type": "gbdt", "trees": [{"nodes": [...]}, ...]}
+// Feature indices are 0-based, in the order of FEATURE_NAMES.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Reranker {
+    Linear {
+        weights: Vec<f64>,
+        #[serde(default)]
+        bias: f64,
+    },
+    Gbdt {
+        trees: Vec<Tree>,
+    },
+}
+
+// A regression tree, starting at nodes[0]. Leaf values are already scaled by the learning rate.
+#[derive(Debug, Deserialize)]
+pub struct Tree {
+    nodes: Vec<Node>,
+}
+
+// Features less than or equal to the threshold go left
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Node {
+    Split {
+        feature: usize,
+        threshold: f64,
+        left: usize,
+        right: usize,
+    },
+    Leaf {
+        value: f64,
+    },
+}
+
+impl Reranker {
+    pub fn load(model_path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(model_path)
+            .map_err(|e| format!("Failed to read reranking model: {e}"))?;
+        let reranker: Reranker = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse reranking model: {e}"))?;
+        reranker.validate()?;
+        Ok(reranker)
+    }
+
+    // Catches models referring to features or nodes that don't exist, so scoring can't fail
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Reranker::Linear { weights, .. } => {
+                if weights.len() > FEATURE_NAMES.len() {
+                    return Err(format!(
+                        "Linear model has {} weights, expected at most {}",
+                        weights.len(),
+                        FEATURE_NAMES.len()
+                    ));
+                }
+            }
+            Reranker::Gbdt { trees } => {
+                for (tree_num, tree) in trees.iter().enumerate() {
+                    if tree.nodes.is_empty() {
+                        return Err(format!("Tree {tree_num} has no nodes"));
+                    }
+                    for (node_num, node) in tree.nodes.iter().enumerate() {
+                        if let Node::Split {
+                            feature,
+                            left,
+                            right,
+                            ..
+                        } = node
+                        {
+                            if *feature >= FEATURE_NAMES.len() {
+                                return Err(format!(
+                                    "Tree {tree_num} splits on unknown feature {feature}"
+                                ));
+                            }
+                            // Children come after their parent, so every path ends in a leaf
+                            let is_child =
+                                |child: usize| node_num < child && child < tree.nodes.len();
+                            if !is_child(*left) || !is_child(*right) {
+                                return Err(format!(
+                                    "Tree {tree_num} node {node_num} has an invalid child"
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn score(&self, features: &[f64]) -> f64 {
+        match self {
+            Reranker::Linear { weights, bias } => {
+                bias + weights
+                    .iter()
+                    .zip(features)
+                    .map(|(weight, feature)| weight * feature)
+                    .sum::<f64>()
+            }
+            Reranker::Gbdt { trees } => trees.iter().map(|tree| tree.score(features)).sum(),
+        }
+    }
+
+    // Scores the results with the model and sorts them by the new scores, keeping the first stage
+    // order for ties. Only the page's results are kept.
+    pub fn rerank(&self, mut results: Vec<QueryResult>, page: Page) -> Vec<QueryResult> {
+        for result in &mut results {
+            result.score = self.score(&result.features);
+        }
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        results
+            .into_iter()
+            .skip(page.offset)
+            .take(page.limit)
+            .collect()
+    }
+}
+
+impl Tree {
+    fn score(&self, features: &[f64]) -> f64 {
+        let mut node = &self.nodes[0];
+        loop {
+            match node {
+                Node::Split {
+                    feature,
+                    threshold,
+                    left,
+                    right,
+                } => {
+                    let value = features.get(*feature).copied().unwrap_or(0.0);
+                    node = &self.nodes[if value <= *threshold { *left } else { *right }];
+                }
+                Node::Leaf { value } => return *value,
+            }
+        }
+    }
+}
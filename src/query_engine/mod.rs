@@ -0,0 +1,13 @@
+pub mod feedback;
+pub mod index_reader;
+pub mod ltr;
+pub mod postings;
+#[allow(clippy::module_inception)]
+pub mod query_engine;
+pub mod query_parser;
+pub mod scoring;
+pub mod spelling;
+pub mod synonyms;
+pub mod term_dictionary;
+pub mod top_scores;
+pub mod wand;
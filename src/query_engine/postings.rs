@@ -0,0 +1,296 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use super::{index_reader::FieldStats, wand::ArticleIdSource};
+use crate::{
+    common::{add_block_max, BlockMaxes, Field},
+    index_files::{PostingsFile, PostingsList},
+};
+
+// The first error decoding any postings block of a query. Lookups can't fail halfway through
+// ranking, so a block that fails to decode is left empty and the error is reported once the
+// query is done.
+#[derive(Clone, Default)]
+pub struct DecodeError(Rc<RefCell<Option<String>>>);
+
+impl DecodeError {
+    fn record<T: Default>(&self, result: Result<T, String>) -> T {
+        result.unwrap_or_else(|e| {
+            self.0.borrow_mut().get_or_insert(e);
+            T::default()
+        })
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        match self.0.borrow().as_ref() {
+            Some(e) => Err(format!("Failed to decode postings: {e}")),
+            None => Ok(()),
+        }
+    }
+}
+
+// A token's postings list in a field, each block decoded the first time it's looked into and its
+// positions only the first time they're needed
+pub struct LazyPostings<'a> {
+    list: PostingsList<'a>,
+    // Article IDs and frequencies of every block
+    articles: Vec<OnceCell<Vec<(usize, usize)>>>,
+    positions: Vec<OnceCell<Vec<Vec<usize>>>>,
+    error: DecodeError,
+}
+
+impl<'a> LazyPostings<'a> {
+    fn new(list: PostingsList<'a>, error: DecodeError) -> Self {
+        LazyPostings {
+            articles: list.blocks.iter().map(|_| OnceCell::new()).collect(),
+            positions: list.blocks.iter().map(|_| OnceCell::new()).collect(),
+            list,
+            error,
+        }
+    }
+
+    fn block_articles(&self, block: usize) -> &[(usize, usize)] {
+        self.articles[block].get_or_init(|| self.error.record(self.list.blocks[block].articles()))
+    }
+
+    fn block_positions(&self, block: usize) -> &[Vec<usize>] {
+        self.positions[block].get_or_init(|| {
+            self.error
+                .record(self.list.blocks[block].positions(self.block_articles(block)))
+        })
+    }
+
+    // Index of the block the article would be in and of the article in it, only decoding the block
+    // if the article is within its first and last article IDs
+    fn find(&self, article_id: usize) -> Option<(usize, usize)> {
+        let block = self
+            .list
+            .blocks
+            .partition_point(|block| block.last_article_id < article_id);
+        if self.list.blocks.get(block)?.first_article_id > article_id {
+            return None;
+        }
+        let index = self
+            .block_articles(block)
+            .binary_search_by_key(&article_id, |(article_id, _)| *article_id)
+            .ok()?;
+        Some((block, index))
+    }
+
+    fn frequency(&self, article_id: usize) -> Option<usize> {
+        let (block, index) = self.find(article_id)?;
+        Some(self.block_articles(block).get(index)?.1)
+    }
+
+    fn positions(&self, article_id: usize) -> Option<&[usize]> {
+        let (block, index) = self.find(article_id)?;
+        self.block_positions(block).get(index).map(Vec::as_slice)
+    }
+
+    fn article_ids(&self) -> Vec<usize> {
+        let mut article_ids = Vec::with_capacity(self.list.doc_freq);
+        for block in 0..self.list.blocks.len() {
+            article_ids.extend(
+                self.block_articles(block)
+                    .iter()
+                    .map(|(article_id, _)| *article_id),
+            );
+        }
+        article_ids
+    }
+}
+
+impl ArticleIdSource for LazyPostings<'_> {
+    // Articles before a block's first one don't need it decoded
+    fn next_at_or_after(&self, article_id: usize) -> Option<usize> {
+        let block = self
+            .list
+            .blocks
+            .partition_point(|block| block.last_article_id < article_id);
+        let first_article_id = self.list.blocks.get(block)?.first_article_id;
+        if first_article_id >= article_id {
+            return Some(first_article_id);
+        }
+        let articles = self.block_articles(block);
+        let index = articles.partition_point(|(id, _)| *id < article_id);
+        articles.get(index).map(|(article_id, _)| *article_id)
+    }
+}
+
+// Where a token or phrase occurs in a field's articles. Tokens read theirs from their postings,
+// while phrases are matched against their tokens' positions when the query is ranked.
+pub enum Occurrences<'a> {
+    Postings(LazyPostings<'a>),
+    // Article ID -> start positions of the phrase
+    Positions(BTreeMap<usize, Vec<usize>>),
+}
+
+impl Occurrences<'_> {
+    pub fn frequency(&self, article_id: usize) -> Option<usize> {
+        match self {
+            Occurrences::Postings(postings) => postings.frequency(article_id),
+            Occurrences::Positions(positions) => positions.get(&article_id).map(Vec::len),
+        }
+    }
+
+    pub fn positions(&self, article_id: usize) -> Option<&[usize]> {
+        match self {
+            Occurrences::Postings(postings) => postings.positions(article_id),
+            Occurrences::Positions(positions) => positions.get(&article_id).map(Vec::as_slice),
+        }
+    }
+
+    pub fn contains(&self, article_id: usize) -> bool {
+        self.frequency(article_id).is_some()
+    }
+
+    // Number of articles it occurs in
+    pub fn doc_freq(&self) -> usize {
+        match self {
+            Occurrences::Postings(postings) => postings.list.doc_freq,
+            Occurrences::Positions(positions) => positions.len(),
+        }
+    }
+
+    // Occurrences across all articles
+    pub fn collection_freq(&self) -> usize {
+        match self {
+            Occurrences::Postings(postings) => postings.list.collection_freq,
+            Occurrences::Positions(positions) => positions.values().map(Vec::len).sum(),
+        }
+    }
+
+    // In increasing order, decoding every block
+    pub fn article_ids(&self) -> Vec<usize> {
+        match self {
+            Occurrences::Postings(postings) => postings.article_ids(),
+            Occurrences::Positions(positions) => positions.keys().copied().collect(),
+        }
+    }
+
+    // Tokens have theirs stored in their block headers, phrases get theirs from their occurrences
+    pub fn block_maxes(&self, field_stats: &FieldStats) -> BlockMaxes {
+        match self {
+            Occurrences::Postings(postings) => postings.list.block_maxes(),
+            Occurrences::Positions(positions) => {
+                let mut block_maxes = BlockMaxes::new();
+                for (article_id, positions) in positions {
+                    add_block_max(
+                        &mut block_maxes,
+                        *article_id,
+                        positions.len(),
+                        field_stats.length(*article_id),
+                        field_stats.norm(*article_id),
+                    );
+                }
+                block_maxes
+            }
+        }
+    }
+}
+
+impl ArticleIdSource for Occurrences<'_> {
+    fn next_at_or_after(&self, article_id: usize) -> Option<usize> {
+        match self {
+            Occurrences::Postings(postings) => postings.next_at_or_after(article_id),
+            Occurrences::Positions(positions) => {
+                positions.range(article_id..).next().map(|(id, _)| *id)
+            }
+        }
+    }
+}
+
+// The occurrences of every token and phrase of a query in each field, read once however many
+// terms they're in and referred to by their index
+pub struct QueryPostings<'a> {
+    postings: &'a HashMap<Field, PostingsFile>,
+    occurrences: Vec<Occurrences<'a>>,
+    // (field, token ID) -> index of its postings, None if it has none in the field
+    tokens: HashMap<(Field, usize), Option<usize>>,
+    // (field, token IDs, slop) -> index of the phrase's occurrences
+    phrases: HashMap<(Field, Vec<usize>, usize), usize>,
+    error: DecodeError,
+}
+
+impl<'a> QueryPostings<'a> {
+    pub fn new(postings: &'a HashMap<Field, PostingsFile>) -> Self {
+        QueryPostings {
+            postings,
+            occurrences: Vec::new(),
+            tokens: HashMap::new(),
+            phrases: HashMap::new(),
+            error: DecodeError::default(),
+        }
+    }
+
+    pub fn token(&mut self, field: Field, token_id: usize) -> Result<Option<usize>, String> {
+        if let Some(index) = self.tokens.get(&(field, token_id)) {
+            return Ok(*index);
+        }
+        let index = match self.postings[&field].list(token_id)? {
+            Some(list) => {
+                let postings = LazyPostings::new(list, self.error.clone());
+                self.occurrences.push(Occurrences::Postings(postings));
+                Some(self.occurrences.len() - 1)
+            }
+            None => None,
+        };
+        self.tokens.insert((field, token_id), index);
+        Ok(index)
+    }
+
+    // Matches the phrase with `positions` from its tokens' postings the first time it's needed
+    pub fn phrase(
+        &mut self,
+        field: Field,
+        token_ids: &[usize],
+        slop: usize,
+        positions: impl FnOnce(&Self) -> BTreeMap<usize, Vec<usize>>,
+    ) -> usize {
+        let key = (field, token_ids.to_vec(), slop);
+        if let Some(index) = self.phrases.get(&key) {
+            return *index;
+        }
+        let phrase_positions = positions(self);
+        self.occurrences
+            .push(Occurrences::Positions(phrase_positions));
+        self.phrases.insert(key, self.occurrences.len() - 1);
+        self.occurrences.len() - 1
+    }
+
+    pub fn get(&self, index: usize) -> &Occurrences<'a> {
+        &self.occurrences[index]
+    }
+
+    pub fn contains(&self, indices: &[usize], article_id: usize) -> bool {
+        indices
+            .iter()
+            .any(|index| self.occurrences[*index].contains(article_id))
+    }
+
+    // Positions of any of the occurrences in the article, None if none of them are in it
+    pub fn positions(&self, indices: &[usize], article_id: usize) -> Option<Vec<usize>> {
+        let mut positions = Vec::new();
+        let mut found = false;
+        for index in indices {
+            if let Some(occurrence_positions) = self.occurrences[*index].positions(article_id) {
+                positions.extend_from_slice(occurrence_positions);
+                found = true;
+            }
+        }
+        if !found {
+            return None;
+        }
+        positions.sort_unstable();
+        positions.dedup();
+        Some(positions)
+    }
+
+    // Shared with every block decoded for the query, including after ranking
+    pub fn decode_error(&self) -> DecodeError {
+        self.error.clone()
+    }
+}
@@ -0,0 +1,1920 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::{
+    feedback::Feedback,
+    index_reader::{FieldStats, IndexReader, LsaIndex, SearchOptions},
+    postings::QueryPostings,
+    query_parser::{parse_query, Query},
+    scoring::{Bm25, Explanation, Scorer, TermStats},
+    spelling::{auto_fuzzy_distance, suggest_query},
+    synonyms::{Alternative, ExpandedTerm, SynonymMap},
+    term_dictionary::TermDictionary,
+    top_scores::TopScores,
+    wand::{self, ArticleIdSource, PostingsCursor},
+};
+use crate::{
+    common::{
+        cosine_similarity, exact_token, minhash_similarity, token_offsets, tokenize,
+        tokenize_exact, BlockMaxes, Field, IndexConfig, QueryResult, QueryResults,
+        DUPLICATE_SIMILARITY, EXACT_MATCH_WEIGHT, EXACT_TOKEN_MARKER, FUZZY_MATCH_WEIGHT,
+        MAX_FUZZY_EXPANSIONS, MAX_WILDCARD_EXPANSIONS,
+    },
+    index_files::{ArticleStats, Docstore, PostingsFile},
+};
+
+// The page of results of the query, out of its results ranked best first. Ties are broken by
+// article ID so the same query always pages the same way.
+pub fn get_query_results(
+    query: &str,
+    reader: &IndexReader,
+    synonyms: &SynonymMap,
+    options: &SearchOptions,
+) -> Result<QueryResults, String> {
+    let parsed_query = parse_query(query).map_err(|e| e.to_string())?;
+    let page = options.page;
+    let minhashes = match options.ranking_params.collapse_duplicates {
+        Some(false) => None,
+        _ => Some(&reader.minhashes),
+    };
+    let num_results = page.offset.saturating_add(page.limit);
+    // Collapsing near-duplicates can leave fewer results than the articles ranked, in which case
+    // the query is run again ranking more of them
+    let mut num_top = num_results;
+    loop {
+        let query_results = run_query(
+            &parsed_query,
+            reader,
+            synonyms,
+            options,
+            Some(num_top),
+            |QueryRun {
+                 ranking:
+                     Ranking {
+                         scores,
+                         num_matches,
+                         all_matches_counted,
+                         scoring,
+                         proximity_terms,
+                         term_matches,
+                         ..
+                     },
+                 num_hits,
+                 index,
+                 ..
+             }| {
+                // Article ID, score and number of duplicates of every result up to the page's end
+                let mut kept: Vec<(usize, f64, usize)> = Vec::new();
+                // Index of each result in kept with its signature
+                let mut kept_signatures: Vec<(usize, Vec<u64>)> = Vec::new();
+
+                // Lower ranked near-duplicates of a result are counted on it instead of being shown.
+                // Once the results are full, the rest of the ranked articles are only checked for that.
+                for (article_id, score) in &scores {
+                    // Articles too short for a signature have all zeros
+                    let signature = minhashes
+                        .and_then(|minhashes| minhashes.row(*article_id))
+                        .filter(|signature| signature.iter().any(|hash| *hash != 0));
+                    if let Some(signature) = &signature {
+                        if let Some((kept_index, _)) =
+                            kept_signatures.iter().find(|(_, kept_signature)| {
+                                minhash_similarity(signature, kept_signature)
+                                    >= DUPLICATE_SIMILARITY
+                            })
+                        {
+                            kept[*kept_index].2 += 1;
+                            continue;
+                        }
+                    }
+                    if kept.len() >= num_results {
+                        if minhashes.is_none() {
+                            break;
+                        }
+                        continue;
+                    }
+                    if let Some(signature) = signature {
+                        kept_signatures.push((kept.len(), signature));
+                    }
+                    kept.push((*article_id, *score, 0));
+                }
+                if kept.len() < num_results && (scores.len() < num_matches || !all_matches_counted)
+                {
+                    return Ok(None);
+                }
+
+                let mut query_results = Vec::new();
+                for (article_id, score, duplicates) in kept.into_iter().skip(page.offset) {
+                    let article = match index.docstore.get(article_id) {
+                        Ok(article) => article,
+                        Err(e) => {
+                            eprintln!("Failed to get article {}: {}", article_id, e);
+                            continue;
+                        }
+                    };
+                    let article_snippet = match get_article_snippet(
+                        article_id,
+                        &article.text,
+                        &scoring,
+                        index.index_config.snippet_offset,
+                    ) {
+                        Ok(snippet) => snippet,
+                        Err(e) => {
+                            eprintln!("Failed to get snippet for article {}: {}", article_id, e);
+                            continue;
+                        }
+                    };
+                    query_results.push(QueryResult {
+                        article_id,
+                        title: article.title,
+                        snippet: article_snippet,
+                        score,
+                        features: extract_features(
+                            article_id,
+                            score,
+                            &scoring,
+                            &proximity_terms,
+                            &term_matches,
+                            &reader.inlinks,
+                        ),
+                        duplicates,
+                    });
+                }
+
+                Ok(Some(QueryResults {
+                    results: query_results,
+                    total_hits: num_matches,
+                    exact_total_hits: all_matches_counted,
+                    suggestion: suggest_query(
+                        query,
+                        &parsed_query,
+                        index.term_dictionary,
+                        num_hits,
+                    ),
+                }))
+            },
+        )?;
+        match query_results {
+            Some(query_results) => return Ok(query_results),
+            None => num_top = num_top.saturating_mul(2),
+        }
+    }
+}
+
+// The trees of score contributions of the articles, as they're ranked by get_query_results. The
+// query is only ranked once for all of them.
+pub fn explain_query(
+    query: &str,
+    article_ids: &[usize],
+    reader: &IndexReader,
+    synonyms: &SynonymMap,
+    options: &SearchOptions,
+) -> Result<Vec<Explanation>, String> {
+    let parsed_query = parse_query(query).map_err(|e| e.to_string())?;
+    let proximity_weight = options.proximity_weight;
+    run_query(
+        &parsed_query,
+        reader,
+        synonyms,
+        options,
+        None,
+        |QueryRun {
+             ranking, scorer, ..
+         }| {
+            let scores = ranking
+                .scores
+                .iter()
+                .copied()
+                .collect::<HashMap<usize, f64>>();
+            let explain = |article_id: usize| {
+                let score = match scores.get(&article_id) {
+                    Some(score) => *score,
+                    None => {
+                        return Explanation::new(
+                            0.0,
+                            format!("article {article_id} doesn't match the query"),
+                        )
+                    }
+                };
+                let terms = ranking.scoring.explain(article_id, scorer);
+                let proximity_boost = Explanation::new(
+                    proximity_boost(
+                        article_id,
+                        &ranking.proximity_terms,
+                        &ranking.scoring.postings,
+                        proximity_weight,
+                    ),
+                    format!("proximity boost, weight {proximity_weight}"),
+                );
+                let semantic = match &ranking.semantic {
+                    Some(semantic) => semantic,
+                    None => {
+                        return Explanation::new(
+                            score,
+                            format!("score of article {article_id}, sum of:"),
+                        )
+                        .with_details(vec![terms, proximity_boost])
+                    }
+                };
+                let lexical_score = Explanation::new(
+                    terms.value + proximity_boost.value,
+                    "ranking model score, sum of:",
+                )
+                .with_details(vec![terms, proximity_boost]);
+                Explanation::new(
+                    score,
+                    format!(
+                        "score of article {article_id}, (1 - w) * ranking model score / max score + w * LSA similarity with w {}:",
+                        semantic.weight
+                    ),
+                )
+                .with_details(vec![
+                    lexical_score,
+                    Explanation::new(semantic.max_score, "max score of the matching articles"),
+                    Explanation::new(
+                        semantic.similarities[&article_id],
+                        "cosine similarity of the article and query LSA vectors",
+                    ),
+                ])
+            };
+            Ok(article_ids
+                .iter()
+                .map(|article_id| explain(*article_id))
+                .collect())
+        },
+    )
+}
+
+// What a query was evaluated against and the resulting ranking
+struct QueryRun<'a> {
+    ranking: Ranking<'a>,
+    // Number of articles matching the query before any feedback, None if only the top articles
+    // were counted
+    num_hits: Option<usize>,
+    index: &'a IndexContext<'a>,
+    scorer: &'a dyn Scorer,
+}
+
+// Ranks the articles for the query, with a second feedback pass if it's turned on, and hands the
+// ranking of the best `num_top` articles (or all of them if it's None) over to `handle`
+fn run_query<T>(
+    parsed_query: &Query,
+    reader: &IndexReader,
+    synonyms: &SynonymMap,
+    options: &SearchOptions,
+    num_top: Option<usize>,
+    handle: impl FnOnce(QueryRun) -> Result<T, String>,
+) -> Result<T, String> {
+    let SearchOptions {
+        ranking_model,
+        ranking_params,
+        proximity_weight,
+        ..
+    } = options;
+    let proximity_weight = *proximity_weight;
+    let index_config = reader.index_config.with_params(ranking_params);
+    let scorer = ranking_model.scorer(&index_config);
+
+    let clause = compile_query(parsed_query, synonyms, &reader.term_dictionary);
+    let index = IndexContext {
+        term_dictionary: &reader.term_dictionary,
+        field_stats: &reader.field_stats,
+        postings: &reader.postings,
+        docstore: &reader.docstore,
+        num_articles: reader.num_articles,
+        index_config: &index_config,
+        lsa: reader.lsa.as_ref(),
+    };
+
+    let num_query_terms = clause.num_terms();
+    let feedback = Feedback::from_params(ranking_params);
+    let semantic_weight = ranking_params
+        .semantic_weight
+        .filter(|weight| *weight > 0.0)
+        .map(|weight| weight.min(1.0));
+    // Blending in the LSA similarities can move any matching article to the top, so they all need
+    // to be ranked first
+    let num_ranked = match semantic_weight {
+        Some(_) => None,
+        None => num_top,
+    };
+
+    let ranking = rank(
+        &clause,
+        num_query_terms,
+        &index,
+        scorer.as_ref(),
+        proximity_weight,
+        match &feedback {
+            Some(feedback) => Some(feedback.num_docs),
+            None => num_ranked,
+        },
+    )?;
+    let num_hits = ranking.all_matches_counted.then_some(ranking.num_matches);
+    // Second pass with the query expanded by terms from the top articles of the first one
+    let (clause, mut ranking) = match feedback {
+        Some(feedback) => {
+            let clause = feedback_clause(clause, &ranking.scores, &feedback, &index)?;
+            let ranking = rank(
+                &clause,
+                num_query_terms,
+                &index,
+                scorer.as_ref(),
+                proximity_weight,
+                num_ranked,
+            )?;
+            (clause, ranking)
+        }
+        None => (clause, ranking),
+    };
+    if let Some(semantic_weight) = semantic_weight {
+        blend_semantic(&mut ranking, &clause, semantic_weight, &index, num_top)?;
+    }
+
+    // Postings blocks are still decoded for snippets and features after ranking
+    let decode_error = ranking.scoring.postings.decode_error();
+    let result = handle(QueryRun {
+        ranking,
+        num_hits,
+        index: &index,
+        scorer: scorer.as_ref(),
+    })?;
+    decode_error.check()?;
+    Ok(result)
+}
+
+// The index files a query is evaluated against, with the index config for the query's parameters
+struct IndexContext<'a> {
+    term_dictionary: &'a TermDictionary,
+    field_stats: &'a HashMap<Field, FieldStats>,
+    postings: &'a HashMap<Field, PostingsFile>,
+    docstore: &'a Docstore,
+    num_articles: usize,
+    index_config: &'a IndexConfig,
+    lsa: Option<&'a LsaIndex>,
+}
+
+// The scores of the articles matching a query, best first, and what they were scored with
+struct Ranking<'a> {
+    scores: Vec<(usize, f64)>,
+    // Number of articles matching the query, `scores` may only hold the best of them. Without
+    // all_matches_counted it's a lower bound, the articles WAND skipped weren't checked.
+    num_matches: usize,
+    all_matches_counted: bool,
+    scoring: ScoringContext<'a>,
+    // Field -> occurrences of every query term in it, as indices into the query postings
+    proximity_terms: HashMap<Field, Vec<Vec<usize>>>,
+    term_matches: Vec<TermMatch>,
+    semantic: Option<SemanticBlend>,
+}
+
+// How the ranking model's scores were blended with the LSA similarities of the articles
+struct SemanticBlend {
+    weight: f64,
+    // Scores are divided by the best one so they're on the same scale as cosine similarities
+    max_score: f64,
+    // Article ID -> cosine similarity to the query
+    similarities: HashMap<usize, f64>,
+}
+
+// Only the first `num_query_terms` terms of the clause count towards proximity and term matches,
+// the rest are feedback expansion terms that just add to the score. Only the best `num_top`
+// articles are kept in the ranking, or all of them if it's None.
+fn rank<'a>(
+    clause: &Clause,
+    num_query_terms: usize,
+    index: &IndexContext<'a>,
+    scorer: &dyn Scorer,
+    proximity_weight: f64,
+    num_top: Option<usize>,
+) -> Result<Ranking<'a>, String> {
+    let IndexContext {
+        term_dictionary,
+        field_stats,
+        postings,
+        index_config,
+        num_articles,
+        ..
+    } = *index;
+    let mut terms = Vec::new();
+    clause.collect_terms(false, 1.0, &mut terms);
+
+    // Term -> field -> occurrences of its alternatives, as indices into the query postings.
+    // Negated terms are read too since they still decide which articles match.
+    let mut query_postings = QueryPostings::new(postings);
+    let mut term_occurrences: Vec<Vec<(Field, Vec<usize>)>> = Vec::new();
+    for term in &terms {
+        let mut fields = Vec::new();
+        for field in search_fields(term.field) {
+            let mut occurrences = Vec::new();
+            for alternative in &term.expanded_term.alternatives {
+                occurrences.extend(alternative_occurrences(
+                    alternative,
+                    field,
+                    term_dictionary,
+                    &mut query_postings,
+                )?);
+            }
+            fields.push((field, occurrences));
+        }
+        term_occurrences.push(fields);
+    }
+
+    // Articles are ranked by the terms they should contain, each of which is a weighted OR of its
+    // alternatives. Single token alternatives are folded into the query token frequencies while
+    // multi-token ones are scored as phrases. Terms without a field are scored in every field.
+    // A token in several terms with different boosts uses the largest one.
+    let mut query_token_freqs: BTreeMap<(Field, usize), (f64, f64)> = BTreeMap::new();
+    let mut tokens: HashMap<usize, &str> = HashMap::new();
+    let mut scoring_terms = Vec::new();
+    let mut proximity_terms: HashMap<Field, Vec<Vec<usize>>> = HashMap::new();
+    let mut term_matches = Vec::new();
+    for (
+        term_index,
+        (
+            QueryTerm {
+                expanded_term,
+                negated,
+                boost,
+                ..
+            },
+            fields,
+        ),
+    ) in terms.iter().zip(&term_occurrences).enumerate()
+    {
+        if *negated {
+            continue;
+        }
+        let boost = *boost;
+        let is_query_term = term_index < num_query_terms;
+        let mut term_match = TermMatch {
+            occurrences: Vec::new(),
+            title_occurrences: None,
+        };
+        for (field, occurrences) in fields {
+            let field = *field;
+            if is_query_term {
+                term_match.occurrences.extend(occurrences);
+                if field == Field::Title {
+                    term_match.title_occurrences = Some(occurrences.clone());
+                }
+                proximity_terms
+                    .entry(field)
+                    .or_default()
+                    .push(occurrences.clone());
+            }
+            for (alternative_index, alternative) in expanded_term.alternatives.iter().enumerate() {
+                match alternative.tokens.as_slice() {
+                    [token] => {
+                        if let Some(token_id) = term_dictionary.get(token) {
+                            tokens.insert(token_id, token.as_str());
+                            let (query_freq, token_boost) = query_token_freqs
+                                .entry((field, token_id))
+                                .or_insert((0.0, boost));
+                            *query_freq += alternative.weight;
+                            *token_boost = token_boost.max(boost);
+                        }
+                    }
+                    phrase_tokens => {
+                        let occurrences = alternative_occurrences(
+                            alternative,
+                            field,
+                            term_dictionary,
+                            &mut query_postings,
+                        )?;
+                        let occurrences_list = occurrences.map(|index| query_postings.get(index));
+                        scoring_terms.push(ScoringTerm {
+                            key: TermKey::Phrase(term_index, alternative_index),
+                            term: format!("\"{}\"", phrase_tokens.join(" ")),
+                            occurrences,
+                            field,
+                            doc_freq: occurrences_list.map_or(0, |list| list.doc_freq()) as f64,
+                            collection_freq: occurrences_list
+                                .map_or(0, |list| list.collection_freq())
+                                as f64,
+                            num_tokens: phrase_tokens.len(),
+                            query_freq: alternative.weight,
+                            boost,
+                        })
+                    }
+                }
+            }
+        }
+        if is_query_term {
+            term_matches.push(term_match);
+        }
+    }
+    for ((field, token_id), (query_freq, boost)) in query_token_freqs {
+        let occurrences = match query_postings.token(field, token_id)? {
+            Some(occurrences) => occurrences,
+            None => continue,
+        };
+        let occurrences_list = query_postings.get(occurrences);
+        scoring_terms.push(ScoringTerm {
+            key: TermKey::Token(token_id),
+            term: tokens[&token_id].to_string(),
+            occurrences: Some(occurrences),
+            field,
+            doc_freq: occurrences_list.doc_freq() as f64,
+            collection_freq: occurrences_list.collection_freq() as f64,
+            num_tokens: 1,
+            query_freq,
+            boost,
+        });
+    }
+
+    // Number of articles containing each term in any field. Tokens scored in every field have it
+    // in the term dictionary and terms scored in a single field have their own, only the rest
+    // need their articles counted.
+    let mut term_fields: HashMap<TermKey, Vec<&ScoringTerm>> = HashMap::new();
+    for scoring_term in &scoring_terms {
+        term_fields
+            .entry(scoring_term.key)
+            .or_default()
+            .push(scoring_term);
+    }
+    let any_field_doc_freqs = term_fields
+        .into_iter()
+        .map(|(key, fields)| {
+            let doc_freq = match (key, fields.as_slice()) {
+                (_, [scoring_term]) => scoring_term.doc_freq,
+                (TermKey::Token(token_id), _) if fields.len() == Field::ALL.len() => {
+                    term_dictionary.doc_freq(token_id) as f64
+                }
+                _ => fields
+                    .iter()
+                    .filter_map(|scoring_term| scoring_term.occurrences)
+                    .flat_map(|occurrences| query_postings.get(occurrences).article_ids())
+                    .collect::<HashSet<usize>>()
+                    .len() as f64,
+            };
+            (key, doc_freq)
+        })
+        .collect::<HashMap<TermKey, f64>>();
+
+    let scoring = ScoringContext {
+        scoring_terms,
+        any_field_doc_freqs,
+        postings: query_postings,
+        field_stats,
+        index_config,
+        num_articles,
+    };
+    let matcher = Matcher::new(clause, &term_occurrences, &mut 0);
+
+    // Only articles in the postings of a term that isn't negated can match, so articles without
+    // any of them are never looked at. That also keeps queries with nothing but negated terms from
+    // matching every other article with no score.
+    let mut num_matches = 0;
+    let mut score_article = |article_id: usize| {
+        if !matcher.matches(article_id, &scoring.postings) {
+            return None;
+        }
+        num_matches += 1;
+        Some(
+            scoring.score(article_id, scorer)
+                + proximity_boost(
+                    article_id,
+                    &proximity_terms,
+                    &scoring.postings,
+                    proximity_weight,
+                ),
+        )
+    };
+    let mut top_scores = TopScores::new(num_top);
+    // Only the top articles are needed, so the ones that can't make it don't have to be scored,
+    // or even decoded
+    let all_matches_counted =
+        if num_top.is_some() && has_monotonic_scores(index_config, &scoring.scoring_terms) {
+            let block_maxes = get_block_maxes(&scoring);
+            let cursors = scoring.postings_cursors(&block_maxes, scorer);
+            // Proximity boosts are largest with every query term of a field next to each other
+            let proximity_bound = proximity_weight.max(0.0)
+                * proximity_terms
+                    .values()
+                    .map(|terms| terms.len().saturating_sub(1))
+                    .max()
+                    .unwrap_or(0) as f64;
+            wand::collect_top_scores(
+                cursors,
+                proximity_bound,
+                &mut top_scores,
+                &mut score_article,
+            );
+            // Nothing was skipped until the top scores filled up
+            top_scores.threshold().is_none()
+        } else {
+            let candidate_article_ids = scoring
+                .scoring_terms
+                .iter()
+                .filter_map(|scoring_term| scoring_term.occurrences)
+                .flat_map(|occurrences| scoring.postings.get(occurrences).article_ids())
+                .collect::<BTreeSet<usize>>();
+            for article_id in candidate_article_ids {
+                if let Some(score) = score_article(article_id) {
+                    top_scores.push(article_id, score);
+                }
+            }
+            true
+        };
+    scoring.postings.decode_error().check()?;
+
+    Ok(Ranking {
+        scores: top_scores.into_sorted_vec(),
+        num_matches,
+        all_matches_counted,
+        scoring,
+        proximity_terms,
+        term_matches,
+        semantic: None,
+    })
+}
+
+// Rescores the matching articles with a weighted average of their normalized scores and their
+// LSA cosine similarity to the query, keeping the best `num_top` of them
+fn blend_semantic(
+    ranking: &mut Ranking,
+    clause: &Clause,
+    semantic_weight: f64,
+    index: &IndexContext,
+    num_top: Option<usize>,
+) -> Result<(), String> {
+    let lsa = index
+        .lsa
+        .ok_or("The index has no LSA model, build it with --lsa-dims")?;
+
+    let mut terms = Vec::new();
+    clause.collect_terms(false, 1.0, &mut terms);
+    let mut token_weights: HashMap<usize, f64> = HashMap::new();
+    for term in terms.iter().filter(|term| !term.negated) {
+        for alternative in &term.expanded_term.alternatives {
+            for token in &alternative.tokens {
+                if let Some(token_id) = index.term_dictionary.get(token) {
+                    *token_weights.entry(token_id).or_insert(0.0) +=
+                        alternative.weight * term.boost;
+                }
+            }
+        }
+    }
+    let query_vector = lsa
+        .model
+        .project(&token_weights.into_iter().collect::<Vec<(usize, f64)>>());
+
+    let max_score = ranking
+        .scores
+        .iter()
+        .map(|(_, score)| *score)
+        .fold(0.0, f64::max);
+    let similarities = ranking
+        .scores
+        .iter()
+        .map(|(article_id, _)| {
+            let similarity = lsa
+                .article_vectors
+                .row(*article_id)
+                .map_or(0.0, |article_vector| {
+                    cosine_similarity(&query_vector, &article_vector)
+                });
+            (*article_id, similarity)
+        })
+        .collect::<HashMap<usize, f64>>();
+    let mut top_scores = TopScores::new(num_top);
+    for (article_id, score) in &ranking.scores {
+        let normalized_score = if max_score > 0.0 {
+            *score / max_score
+        } else {
+            0.0
+        };
+        top_scores.push(
+            *article_id,
+            (1.0 - semantic_weight) * normalized_score + semantic_weight * similarities[article_id],
+        );
+    }
+    ranking.scores = top_scores.into_sorted_vec();
+
+    ranking.semantic = Some(SemanticBlend {
+        weight: semantic_weight,
+        max_score,
+        similarities,
+    });
+    Ok(())
+}
+
+// A weighted combination of the query and the feedback model's expansion terms, which come after
+// the query's terms. The expansion only adds articles to the results of plain disjunctions, other
+// queries keep matching what they did.
+fn feedback_clause(
+    clause: Clause,
+    scores: &[(usize, f64)],
+    feedback: &Feedback,
+    index: &IndexContext,
+) -> Result<Clause, String> {
+    let mut documents = Vec::new();
+    for (article_id, score) in scores.iter().take(feedback.num_docs) {
+        let article = index.docstore.get(*article_id)?;
+        let tokens = tokenize(&format!("{}\n{}", article.title, article.text));
+        documents.push((tokens, *score));
+    }
+
+    let mut terms = Vec::new();
+    clause.collect_terms(false, 1.0, &mut terms);
+    let query_tokens = terms
+        .iter()
+        .flat_map(|term| &term.expanded_term.alternatives)
+        .flat_map(|alternative| &alternative.tokens)
+        .map(|token| token.as_str())
+        .collect::<HashSet<&str>>();
+    // The expansion gets as much weight as the query terms before interpolating
+    let num_query_terms = terms.iter().filter(|term| !term.negated).count();
+
+    let expansion_terms = feedback.expansion_terms(
+        &documents,
+        &query_tokens,
+        index.term_dictionary,
+        index.num_articles,
+    );
+    if expansion_terms.is_empty() || feedback.original_weight >= 1.0 {
+        return Ok(clause);
+    }
+    let expansion = Clause::Or(
+        expansion_terms
+            .into_iter()
+            .map(|(token, weight)| {
+                let term = ExpandedTerm {
+                    tokens: vec![token.clone()],
+                    alternatives: vec![Alternative {
+                        tokens: vec![token],
+                        weight: 1.0,
+                        slop: 0,
+                    }],
+                };
+                Clause::Boost(
+                    Box::new(Clause::Term(term, None)),
+                    weight * num_query_terms as f64,
+                )
+            })
+            .collect(),
+    );
+
+    let is_disjunction = clause.is_disjunction();
+    let mut original = Clause::Boost(Box::new(clause), feedback.original_weight);
+    if !is_disjunction {
+        original = Clause::Required(Box::new(original));
+    }
+    Ok(Clause::Or(vec![
+        original,
+        Clause::Boost(Box::new(expansion), 1.0 - feedback.original_weight),
+    ]))
+}
+
+// Occurrences of a query term in any field, and in the title if the term searches it, as indices
+// into the query postings
+struct TermMatch {
+    occurrences: Vec<usize>,
+    title_occurrences: Option<Vec<usize>>,
+}
+
+// Identifies the same term across fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum TermKey {
+    Token(usize),
+    // Indices of the query term and of its phrase alternative
+    Phrase(usize, usize),
+}
+
+// Something articles are scored against in one field, either a single token or several tokens
+// matched as a phrase
+struct ScoringTerm {
+    key: TermKey,
+    // The token or phrase, for explanations
+    term: String,
+    // Index of its occurrences in the query postings, None for phrases that don't occur
+    occurrences: Option<usize>,
+    field: Field,
+    // Number of articles it occurs in and occurrences across all of them
+    doc_freq: f64,
+    collection_freq: f64,
+    num_tokens: usize,
+    query_freq: f64,
+    // Multiplies the term's contribution to the score
+    boost: f64,
+}
+
+// A parsed query with its terms expanded, ready to be matched against the postings lists.
+// Terms without a field match any field. In an OR with required clauses, only those have to match.
+enum Clause {
+    Term(ExpandedTerm, Option<Field>),
+    And(Vec<Clause>),
+    Or(Vec<Clause>),
+    Not(Box<Clause>),
+    Required(Box<Clause>),
+    Boost(Box<Clause>, f64),
+    Near(Vec<Clause>, usize),
+}
+
+// A term of the query along with whether it's under a NOT and the product of every boost above it
+struct QueryTerm<'a> {
+    expanded_term: &'a ExpandedTerm,
+    field: Option<Field>,
+    negated: bool,
+    boost: f64,
+}
+
+impl Clause {
+    fn is_empty(&self) -> bool {
+        matches!(self, Clause::Or(clauses) if clauses.is_empty())
+    }
+
+    fn num_terms(&self) -> usize {
+        let mut terms = Vec::new();
+        self.collect_terms(false, 1.0, &mut terms);
+        terms.len()
+    }
+
+    // Whether any article containing one of the terms matches
+    fn is_disjunction(&self) -> bool {
+        match self {
+            Clause::Term(..) => true,
+            Clause::Or(clauses) => clauses.iter().all(Clause::is_disjunction),
+            Clause::Boost(clause, _) => clause.is_disjunction(),
+            Clause::And(_) | Clause::Not(_) | Clause::Required(_) | Clause::Near(..) => false,
+        }
+    }
+
+    fn collect_terms<'a>(&'a self, negated: bool, boost: f64, terms: &mut Vec<QueryTerm<'a>>) {
+        match self {
+            Clause::Term(expanded_term, field) => terms.push(QueryTerm {
+                expanded_term,
+                field: *field,
+                negated,
+                boost,
+            }),
+            Clause::And(clauses) | Clause::Or(clauses) | Clause::Near(clauses, _) => {
+                for clause in clauses {
+                    clause.collect_terms(negated, boost, terms);
+                }
+            }
+            Clause::Not(clause) => clause.collect_terms(!negated, boost, terms),
+            Clause::Required(clause) => clause.collect_terms(negated, boost, terms),
+            Clause::Boost(clause, clause_boost) => {
+                clause.collect_terms(negated, boost * clause_boost, terms)
+            }
+        }
+    }
+}
+
+// A clause with the occurrences of its terms, which tells whether an article matches it
+enum Matcher<'a> {
+    // Field -> occurrences of the term's alternatives in it
+    Term(&'a [(Field, Vec<usize>)]),
+    And(Vec<Matcher<'a>>),
+    Or(Vec<Matcher<'a>>),
+    Not(Box<Matcher<'a>>),
+    Required(Box<Matcher<'a>>),
+    Near(Vec<Matcher<'a>>, usize),
+}
+
+impl<'a> Matcher<'a> {
+    // Takes the occurrences of every term of the clause in the order of collect_terms, from
+    // `next_term` on
+    fn new(
+        clause: &Clause,
+        term_occurrences: &'a [Vec<(Field, Vec<usize>)>],
+        next_term: &mut usize,
+    ) -> Self {
+        let matchers = |clauses: &[Clause], next_term: &mut usize| {
+            clauses
+                .iter()
+                .map(|clause| Matcher::new(clause, term_occurrences, next_term))
+                .collect()
+        };
+        match clause {
+            Clause::Term(..) => {
+                *next_term += 1;
+                Matcher::Term(&term_occurrences[*next_term - 1])
+            }
+            Clause::And(clauses) => Matcher::And(matchers(clauses, next_term)),
+            Clause::Or(clauses) => Matcher::Or(matchers(clauses, next_term)),
+            Clause::Not(clause) => {
+                Matcher::Not(Box::new(Matcher::new(clause, term_occurrences, next_term)))
+            }
+            Clause::Required(clause) => {
+                Matcher::Required(Box::new(Matcher::new(clause, term_occurrences, next_term)))
+            }
+            Clause::Boost(clause, _) => Matcher::new(clause, term_occurrences, next_term),
+            Clause::Near(clauses, distance) => {
+                Matcher::Near(matchers(clauses, next_term), *distance)
+            }
+        }
+    }
+
+    // In an OR with required clauses, only those have to match
+    fn matches(&self, article_id: usize, postings: &QueryPostings) -> bool {
+        match self {
+            Matcher::Term(fields) => fields
+                .iter()
+                .any(|(_, occurrences)| postings.contains(occurrences, article_id)),
+            Matcher::And(matchers) => matchers
+                .iter()
+                .all(|matcher| matcher.matches(article_id, postings)),
+            Matcher::Or(matchers) => {
+                let mut required = matchers
+                    .iter()
+                    .filter(|matcher| matches!(matcher, Matcher::Required(_)))
+                    .peekable();
+                if required.peek().is_some() {
+                    required.all(|matcher| matcher.matches(article_id, postings))
+                } else {
+                    matchers
+                        .iter()
+                        .any(|matcher| matcher.matches(article_id, postings))
+                }
+            }
+            Matcher::Not(matcher) => !matcher.matches(article_id, postings),
+            Matcher::Required(matcher) => matcher.matches(article_id, postings),
+            // The operands have to be near each other within the same field
+            Matcher::Near(matchers, distance) => Field::ALL.into_iter().any(|field| {
+                let position_lists = matchers
+                    .iter()
+                    .map(|matcher| matcher.positions(article_id, field, postings))
+                    .collect::<Vec<Vec<usize>>>();
+                !position_lists.is_empty()
+                    && position_lists.iter().all(|positions| !positions.is_empty())
+                    && min_span(
+                        &position_lists
+                            .iter()
+                            .map(Vec::as_slice)
+                            .collect::<Vec<&[usize]>>(),
+                    )
+                    .is_some_and(|span| span <= *distance)
+            }),
+        }
+    }
+
+    // Positions in the field of every term in the clause, regardless of the operators between them
+    fn positions(&self, article_id: usize, field: Field, postings: &QueryPostings) -> Vec<usize> {
+        match self {
+            Matcher::Term(fields) => fields
+                .iter()
+                .filter(|(term_field, _)| *term_field == field)
+                .find_map(|(_, occurrences)| postings.positions(occurrences, article_id))
+                .unwrap_or_default(),
+            Matcher::And(matchers) | Matcher::Or(matchers) | Matcher::Near(matchers, _) => {
+                let mut positions = matchers
+                    .iter()
+                    .flat_map(|matcher| matcher.positions(article_id, field, postings))
+                    .collect::<Vec<usize>>();
+                positions.sort_unstable();
+                positions.dedup();
+                positions
+            }
+            Matcher::Not(matcher) | Matcher::Required(matcher) => {
+                matcher.positions(article_id, field, postings)
+            }
+        }
+    }
+}
+
+fn search_fields(field: Option<Field>) -> Vec<Field> {
+    match field {
+        Some(field) => vec![field],
+        None => Field::ALL.to_vec(),
+    }
+}
+
+// Length of the smallest window of positions containing at least one position from every list
+fn min_span(position_lists: &[&[usize]]) -> Option<usize> {
+    let mut positions = position_lists
+        .iter()
+        .enumerate()
+        .flat_map(|(list, list_positions)| {
+            list_positions.iter().map(move |position| (*position, list))
+        })
+        .collect::<Vec<(usize, usize)>>();
+    positions.sort_unstable();
+
+    let mut list_counts = vec![0; position_lists.len()];
+    let mut num_lists_covered = 0;
+    let mut min_span = None;
+    let mut left = 0;
+    for right in 0..positions.len() {
+        let (_, list) = positions[right];
+        if list_counts[list] == 0 {
+            num_lists_covered += 1;
+        }
+        list_counts[list] += 1;
+
+        while num_lists_covered == position_lists.len() {
+            let span = positions[right].0 - positions[left].0;
+            min_span = Some(min_span.map_or(span, |min_span: usize| min_span.min(span)));
+            let (_, list) = positions[left];
+            list_counts[list] -= 1;
+            if list_counts[list] == 0 {
+                num_lists_covered -= 1;
+            }
+            left += 1;
+        }
+    }
+
+    min_span
+}
+
+// Index in the query postings of where the alternative's tokens occur in order, with at most
+// `slop` other tokens between them, None if they don't all occur in the field
+fn alternative_occurrences(
+    alternative: &Alternative,
+    field: Field,
+    term_dictionary: &TermDictionary,
+    postings: &mut QueryPostings,
+) -> Result<Option<usize>, String> {
+    let mut token_ids = Vec::new();
+    let mut token_occurrences = Vec::new();
+    for token in &alternative.tokens {
+        let token_id = match term_dictionary.get(token) {
+            Some(token_id) => token_id,
+            None => return Ok(None),
+        };
+        match postings.token(field, token_id)? {
+            Some(occurrences) => token_occurrences.push(occurrences),
+            None => return Ok(None),
+        }
+        token_ids.push(token_id);
+    }
+
+    let (first, rest) = match token_occurrences.split_first() {
+        Some((first, [])) => return Ok(Some(*first)),
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let occurrences = postings.phrase(field, &token_ids, alternative.slop, |postings| {
+        let first_occurrences = postings.get(*first);
+        first_occurrences
+            .article_ids()
+            .into_iter()
+            .filter_map(|article_id| {
+                let mut token_positions = vec![first_occurrences.positions(article_id)?];
+                for occurrences in rest {
+                    token_positions.push(postings.get(*occurrences).positions(article_id)?);
+                }
+                let phrase_positions = phrase_positions(&token_positions, alternative.slop);
+                if phrase_positions.is_empty() {
+                    None
+                } else {
+                    Some((article_id, phrase_positions))
+                }
+            })
+            .collect()
+    });
+    Ok(Some(occurrences))
+}
+
+// Start positions of the phrase, given the sorted positions of each of its tokens. Each following
+// token is matched to its closest position after the previous one, which uses up the least slop.
+fn phrase_positions(token_positions: &[&[usize]], slop: usize) -> Vec<usize> {
+    let mut phrase_positions = Vec::new();
+
+    'starts: for start in token_positions[0] {
+        let mut prev_position = *start;
+        let mut slop_left = slop;
+        for positions in &token_positions[1..] {
+            let next = positions.partition_point(|position| *position <= prev_position);
+            match positions.get(next) {
+                Some(position) if position - prev_position - 1 <= slop_left => {
+                    slop_left -= position - prev_position - 1;
+                    prev_position = *position;
+                }
+                _ => continue 'starts,
+            }
+        }
+        phrase_positions.push(*start);
+    }
+
+    phrase_positions
+}
+
+fn compile_query(query: &Query, synonyms: &SynonymMap, term_dictionary: &TermDictionary) -> Clause {
+    match query {
+        Query::Term(term) => {
+            compile_words(&[term.word.as_str()], term.field, synonyms, term_dictionary)
+        }
+        Query::And(operands) => Clause::And(
+            operands
+                .iter()
+                .map(|operand| compile_query(operand, synonyms, term_dictionary))
+                .filter(|clause| !clause.is_empty())
+                .collect(),
+        ),
+        Query::Or(operands) => {
+            // Runs of words in the same field are expanded together so multi-token synonyms can
+            // match across them
+            let mut clauses = Vec::new();
+            let mut words = Vec::new();
+            let mut words_field = None;
+            for operand in operands {
+                match operand {
+                    Query::Term(term) if words.is_empty() || term.field == words_field => {
+                        words.push(term.word.as_str());
+                        words_field = term.field;
+                    }
+                    Query::Term(term) => {
+                        clauses.push(compile_words(
+                            &words,
+                            words_field,
+                            synonyms,
+                            term_dictionary,
+                        ));
+                        words = vec![term.word.as_str()];
+                        words_field = term.field;
+                    }
+                    _ => {
+                        clauses.push(compile_words(
+                            &words,
+                            words_field,
+                            synonyms,
+                            term_dictionary,
+                        ));
+                        words.clear();
+                        clauses.push(compile_query(operand, synonyms, term_dictionary));
+                    }
+                }
+            }
+            clauses.push(compile_words(
+                &words,
+                words_field,
+                synonyms,
+                term_dictionary,
+            ));
+            Clause::Or(
+                clauses
+                    .into_iter()
+                    .filter(|clause| !clause.is_empty())
+                    .collect(),
+            )
+        }
+        Query::Phrase(phrase) => {
+            let tokens = tokenize(&phrase.text);
+            if tokens.is_empty() {
+                return Clause::Or(Vec::new());
+            }
+            Clause::Term(
+                ExpandedTerm {
+                    alternatives: vec![Alternative {
+                        tokens: tokens.clone(),
+                        weight: 1.0,
+                        slop: phrase.slop,
+                    }],
+                    tokens,
+                },
+                phrase.field,
+            )
+        }
+        Query::Wildcard(wildcard) => Clause::Term(
+            expand_wildcard(&wildcard.pattern, term_dictionary),
+            wildcard.field,
+        ),
+        Query::Fuzzy(fuzzy) => {
+            let mut clauses = tokenize(&fuzzy.word)
+                .into_iter()
+                .map(|token| {
+                    Clause::Term(
+                        expand_fuzzy(token, fuzzy.distance, term_dictionary),
+                        fuzzy.field,
+                    )
+                })
+                .collect::<Vec<Clause>>();
+            match clauses.len() {
+                1 => clauses.pop().unwrap(),
+                _ => Clause::Or(clauses),
+            }
+        }
+        Query::Required(operand) => {
+            Clause::Required(Box::new(compile_query(operand, synonyms, term_dictionary)))
+        }
+        Query::Boost(operand, boost) => Clause::Boost(
+            Box::new(compile_query(operand, synonyms, term_dictionary)),
+            *boost,
+        ),
+        Query::Not(operand) => {
+            Clause::Not(Box::new(compile_query(operand, synonyms, term_dictionary)))
+        }
+        Query::Near(operands, distance) => Clause::Near(
+            operands
+                .iter()
+                .map(|operand| compile_query(operand, synonyms, term_dictionary))
+                .collect(),
+            *distance,
+        ),
+    }
+}
+
+fn compile_words(
+    words: &[&str],
+    field: Option<Field>,
+    synonyms: &SynonymMap,
+    term_dictionary: &TermDictionary,
+) -> Clause {
+    let mut clauses = expand_words(words, synonyms, term_dictionary)
+        .into_iter()
+        .map(|expanded_term| Clause::Term(expanded_term, field))
+        .collect::<Vec<Clause>>();
+    match clauses.len() {
+        1 => clauses.pop().unwrap(),
+        _ => Clause::Or(clauses),
+    }
+}
+
+// Parses the query and returns every term in it, expanded the same way as when searching
+pub fn expand_query(
+    query: &str,
+    term_dictionary: &TermDictionary,
+    synonyms: &SynonymMap,
+) -> Result<Vec<(ExpandedTerm, Option<Field>)>, String> {
+    let parsed_query = parse_query(query).map_err(|e| e.to_string())?;
+    let clause = compile_query(&parsed_query, synonyms, term_dictionary);
+    let mut terms = Vec::new();
+    clause.collect_terms(false, 1.0, &mut terms);
+    Ok(terms
+        .into_iter()
+        .map(|term| (term.expanded_term.clone(), term.field))
+        .collect())
+}
+
+// Wildcard patterns match exact (unstemmed) tokens. The expansions are OR'ed and share the weight
+// of a single term, so a pattern matching many tokens doesn't drown out the rest of the query.
+fn expand_wildcard(pattern: &str, term_dictionary: &TermDictionary) -> ExpandedTerm {
+    let pattern = pattern.to_lowercase().replace(
+        |c: char| !(c.is_ascii_alphanumeric() || c == '*' || c == '?'),
+        "",
+    );
+    let expansions =
+        term_dictionary.expand_wildcard(&exact_token(&pattern), MAX_WILDCARD_EXPANSIONS);
+    let weight = 1.0 / expansions.len() as f64;
+
+    ExpandedTerm {
+        tokens: vec![pattern],
+        alternatives: expansions
+            .into_iter()
+            .map(|token| Alternative {
+                tokens: vec![token],
+                weight,
+                slop: 0,
+            })
+            .collect(),
+    }
+}
+
+// Fuzzy matches are weighted down by FUZZY_MATCH_WEIGHT for every edit
+fn expand_fuzzy(token: String, distance: u8, term_dictionary: &TermDictionary) -> ExpandedTerm {
+    ExpandedTerm {
+        alternatives: term_dictionary
+            .expand_fuzzy(&token, distance, MAX_FUZZY_EXPANSIONS)
+            .into_iter()
+            .map(|(fuzzy_token, distance)| Alternative {
+                tokens: vec![fuzzy_token],
+                weight: FUZZY_MATCH_WEIGHT.powi(distance as i32),
+                slop: 0,
+            })
+            .collect(),
+        tokens: vec![token],
+    }
+}
+
+// Expands every word into a weighted OR of its synonyms and its exact (unstemmed) form.
+// Words written as "=word" only match that exact form. Words that aren't in the index at all are
+// fuzzy matched instead, so a typo doesn't drop them from the query.
+fn expand_words(
+    words: &[&str],
+    synonyms: &SynonymMap,
+    term_dictionary: &TermDictionary,
+) -> Vec<ExpandedTerm> {
+    let mut tokens = Vec::new();
+    let mut exact_tokens = Vec::new();
+    for word in words {
+        match word.strip_prefix(EXACT_TOKEN_MARKER) {
+            Some(word) => {
+                let word_exact_tokens = tokenize_exact(word);
+                exact_tokens.extend(word_exact_tokens.iter().map(|_| None));
+                tokens.extend(word_exact_tokens);
+            }
+            None => {
+                tokens.extend(tokenize(word));
+                exact_tokens.extend(tokenize_exact(word).into_iter().map(Some));
+            }
+        }
+    }
+
+    let mut expanded_terms = synonyms.expand(&tokens);
+    let mut start = 0;
+    for expanded_term in &mut expanded_terms {
+        let end = start + expanded_term.tokens.len();
+        // Marked tokens never match a synonym entry, so a term is either fully exact or not at all
+        let term_exact_tokens = exact_tokens[start..end]
+            .iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<String>>();
+        if !term_exact_tokens.is_empty() {
+            expanded_term.alternatives.push(Alternative {
+                tokens: term_exact_tokens.clone(),
+                weight: EXACT_MATCH_WEIGHT,
+                slop: 0,
+            });
+        }
+
+        let unknown = expanded_term.alternatives.iter().all(|alternative| {
+            alternative
+                .tokens
+                .iter()
+                .any(|token| term_dictionary.get(token).is_none())
+        });
+        if let (true, [token], [exact_token]) = (
+            unknown,
+            expanded_term.tokens.as_slice(),
+            term_exact_tokens.as_slice(),
+        ) {
+            // Typos can change the stem a lot, so the word as written is tried as well
+            let distance = auto_fuzzy_distance(token);
+            let fuzzy_term = [token, exact_token]
+                .into_iter()
+                .filter(|_| distance > 0)
+                .map(|token| expand_fuzzy(token.clone(), distance, term_dictionary))
+                .find(|fuzzy_term| !fuzzy_term.alternatives.is_empty());
+            if let Some(mut fuzzy_term) = fuzzy_term {
+                fuzzy_term.tokens = expanded_term.tokens.clone();
+                *expanded_term = fuzzy_term;
+            }
+        }
+        start = end;
+    }
+
+    expanded_terms
+}
+
+// Block upper bounds only hold if scores go up with term frequencies and down with field lengths,
+// which weights, boosts or length normalizations below zero (or b above one) would turn around
+fn has_monotonic_scores(index_config: &IndexConfig, scoring_terms: &[ScoringTerm]) -> bool {
+    index_config.k1 >= 0.0
+        && index_config.k2 >= 0.0
+        && Field::ALL.into_iter().all(|field| {
+            let field_config = index_config.field(field);
+            field_config.weight >= 0.0 && (0.0..=1.0).contains(&field_config.b)
+        })
+        && scoring_terms
+            .iter()
+            .all(|scoring_term| scoring_term.boost >= 0.0 && scoring_term.query_freq >= 0.0)
+}
+
+// Block maxes of every scoring term, in the same order
+fn get_block_maxes(scoring: &ScoringContext) -> Vec<BlockMaxes> {
+    scoring
+        .scoring_terms
+        .iter()
+        .map(|scoring_term| match scoring_term.occurrences {
+            Some(occurrences) => scoring
+                .postings
+                .get(occurrences)
+                .block_maxes(&scoring.field_stats[&scoring_term.field]),
+            None => BlockMaxes::new(),
+        })
+        .collect()
+}
+
+// Everything articles are scored by besides the query's proximity
+struct ScoringContext<'a> {
+    scoring_terms: Vec<ScoringTerm>,
+    // Number of articles containing each term in any field
+    any_field_doc_freqs: HashMap<TermKey, f64>,
+    postings: QueryPostings<'a>,
+    field_stats: &'a HashMap<Field, FieldStats>,
+    index_config: &'a IndexConfig,
+    num_articles: usize,
+}
+
+impl ScoringContext<'_> {
+    fn term_stats(&self, article_id: usize, scoring_term: &ScoringTerm) -> Option<TermStats> {
+        let frequency = self
+            .postings
+            .get(scoring_term.occurrences?)
+            .frequency(article_id)?;
+        let field_stats = &self.field_stats[&scoring_term.field];
+        Some(self.stats(
+            scoring_term,
+            frequency,
+            field_stats.length(article_id),
+            field_stats.norm(article_id),
+        ))
+    }
+
+    fn stats(
+        &self,
+        scoring_term: &ScoringTerm,
+        frequency: usize,
+        field_length: usize,
+        field_norm: f64,
+    ) -> TermStats {
+        let field_stats = &self.field_stats[&scoring_term.field];
+        let field_config = self.index_config.field(scoring_term.field);
+        TermStats {
+            field: scoring_term.field,
+            frequency: frequency as f64,
+            query_freq: scoring_term.query_freq,
+            doc_freq: scoring_term.doc_freq,
+            any_field_doc_freq: self.any_field_doc_freqs[&scoring_term.key],
+            collection_freq: scoring_term.collection_freq,
+            field_length: field_length as f64,
+            average_field_length: field_stats.average_length,
+            field_norm,
+            field_weight: field_config.weight,
+            b: field_config.b,
+            num_articles: self.num_articles as f64,
+        }
+    }
+
+    // A cursor over the occurrences of every term (across the fields it's scored in, like in
+    // score), with the upper bound of its score in each block from the scoring terms' block maxes,
+    // which are in the same order as the scoring terms
+    fn postings_cursors(
+        &self,
+        block_maxes: &[BlockMaxes],
+        scorer: &dyn Scorer,
+    ) -> Vec<PostingsCursor<'_>> {
+        let mut term_fields: HashMap<TermKey, (f64, Vec<usize>)> = HashMap::new();
+        for (i, scoring_term) in self.scoring_terms.iter().enumerate() {
+            term_fields
+                .entry(scoring_term.key)
+                .or_insert((scoring_term.boost, Vec::new()))
+                .1
+                .push(i);
+        }
+
+        term_fields
+            .into_values()
+            .map(|(boost, fields)| {
+                let sources = fields
+                    .iter()
+                    .filter_map(|i| self.scoring_terms[*i].occurrences)
+                    .map(|occurrences| self.postings.get(occurrences) as &dyn ArticleIdSource)
+                    .collect();
+                let blocks = fields
+                    .iter()
+                    .flat_map(|i| block_maxes[*i].keys())
+                    .copied()
+                    .collect::<HashSet<usize>>();
+                let block_max_scores = blocks
+                    .into_iter()
+                    .map(|block| {
+                        let fields = fields
+                            .iter()
+                            .filter_map(|i| {
+                                let block_max = block_maxes[*i].get(&block)?;
+                                Some(self.stats(
+                                    &self.scoring_terms[*i],
+                                    block_max.frequency,
+                                    block_max.field_length,
+                                    block_max.field_norm,
+                                ))
+                            })
+                            .collect::<Vec<TermStats>>();
+                        (block, boost * scorer.score_fields(&fields))
+                    })
+                    .collect();
+                PostingsCursor::new(sources, block_max_scores)
+            })
+            .collect()
+    }
+
+    // The ranking model's score of every term across the fields it occurs in, using the field
+    // weights and length normalization from the index config
+    fn score(&self, article_id: usize, scorer: &dyn Scorer) -> f64 {
+        // Summed in a fixed order so an article always gets exactly the same score
+        let mut score = 0.0;
+        let mut query_lengths: BTreeMap<Field, f64> = BTreeMap::new();
+        let mut term_fields: BTreeMap<TermKey, (f64, Vec<TermStats>)> = BTreeMap::new();
+
+        for scoring_term in &self.scoring_terms {
+            *query_lengths.entry(scoring_term.field).or_insert(0.0) += scoring_term.query_freq;
+            if let Some(term_stats) = self.term_stats(article_id, scoring_term) {
+                term_fields
+                    .entry(scoring_term.key)
+                    .or_insert((scoring_term.boost, Vec::new()))
+                    .1
+                    .push(term_stats);
+            }
+        }
+
+        for (boost, fields) in term_fields.values() {
+            score += boost * scorer.score_fields(fields);
+        }
+
+        for (field, query_length) in query_lengths {
+            let field_length = self.field_stats[&field].length(article_id);
+            score += self.index_config.field(field).weight
+                * scorer.score_field(field_length as f64, query_length);
+        }
+
+        score
+    }
+
+    // Same as score, along with how every term and field contributed to it
+    fn explain(&self, article_id: usize, scorer: &dyn Scorer) -> Explanation {
+        let mut query_lengths: BTreeMap<Field, f64> = BTreeMap::new();
+        let mut term_fields: HashMap<TermKey, (&str, f64, Vec<TermStats>)> = HashMap::new();
+
+        for scoring_term in &self.scoring_terms {
+            *query_lengths.entry(scoring_term.field).or_insert(0.0) += scoring_term.query_freq;
+            if let Some(term_stats) = self.term_stats(article_id, scoring_term) {
+                term_fields
+                    .entry(scoring_term.key)
+                    .or_insert((&scoring_term.term, scoring_term.boost, Vec::new()))
+                    .2
+                    .push(term_stats);
+            }
+        }
+
+        let mut details = term_fields
+            .into_values()
+            .map(|(term, boost, fields)| {
+                let fields = scorer.explain_fields(&fields);
+                Explanation::new(boost * fields.value, format!("{term}, product of:"))
+                    .with_details(vec![Explanation::new(boost, "boost"), fields])
+            })
+            .collect::<Vec<Explanation>>();
+        details.sort_by(|a, b| b.value.total_cmp(&a.value));
+
+        for (field, query_length) in query_lengths {
+            let field_length = self.field_stats[&field].length(article_id);
+            let field_weight = self.index_config.field(field).weight;
+            let prior = scorer.score_field(field_length as f64, query_length);
+            if prior != 0.0 {
+                details.push(Explanation::new(
+                    field_weight * prior,
+                    format!(
+                        "{} field length prior, weight {} * {} for length {} and query length {}",
+                        field.name(),
+                        field_weight,
+                        prior,
+                        field_length,
+                        query_length
+                    ),
+                ));
+            }
+        }
+
+        Explanation::new(
+            details.iter().map(|detail| detail.value).sum(),
+            "sum of the terms:",
+        )
+        .with_details(details)
+    }
+
+    // The ranking model's score of the terms in a single field, without the field weight
+    fn field_score(&self, article_id: usize, field: Field, scorer: &dyn Scorer) -> f64 {
+        self.scoring_terms
+            .iter()
+            .filter(|scoring_term| scoring_term.field == field)
+            .filter_map(|scoring_term| {
+                let term_stats = self.term_stats(article_id, scoring_term)?;
+                Some(scoring_term.boost * scorer.score_term(&term_stats))
+            })
+            .fold(0.0, |score, term_score| score + term_score)
+    }
+}
+
+// Learning to rank features of an article, in the order of FEATURE_NAMES
+fn extract_features(
+    article_id: usize,
+    score: f64,
+    scoring: &ScoringContext,
+    proximity_terms: &HashMap<Field, Vec<Vec<usize>>>,
+    term_matches: &[TermMatch],
+    inlinks: &ArticleStats<u32>,
+) -> Vec<f64> {
+    let bm25 = Bm25 {
+        k1: scoring.index_config.k1,
+        k2: scoring.index_config.k2,
+    };
+    let mut features = Field::ALL
+        .into_iter()
+        .map(|field| scoring.field_score(article_id, field, &bm25))
+        .collect::<Vec<f64>>();
+
+    let num_matched_terms = term_matches
+        .iter()
+        .filter(|term_match| {
+            scoring
+                .postings
+                .contains(&term_match.occurrences, article_id)
+        })
+        .count();
+    features.push(num_matched_terms as f64 / term_matches.len().max(1) as f64);
+
+    features.push(proximity_boost(
+        article_id,
+        proximity_terms,
+        &scoring.postings,
+        1.0,
+    ));
+
+    let text_length = scoring.field_stats[&Field::Text].length(article_id);
+    features.push((1.0 + text_length as f64).ln());
+    let num_inlinks = inlinks.get(article_id).unwrap_or(0);
+    features.push((1.0 + num_inlinks as f64).ln());
+
+    let title_matches = term_matches
+        .iter()
+        .filter_map(|term_match| term_match.title_occurrences.as_ref())
+        .collect::<Vec<&Vec<usize>>>();
+    let num_title_matches = title_matches
+        .iter()
+        .filter(|occurrences| scoring.postings.contains(occurrences, article_id))
+        .count();
+    features.push(num_title_matches as f64 / title_matches.len().max(1) as f64);
+
+    features.push(score);
+    features
+}
+
+// The best proximity boost of any field
+fn proximity_boost(
+    article_id: usize,
+    proximity_terms: &HashMap<Field, Vec<Vec<usize>>>,
+    postings: &QueryPostings,
+    proximity_weight: f64,
+) -> f64 {
+    proximity_terms
+        .values()
+        .map(|proximity_terms| {
+            calculate_proximity_boost(article_id, proximity_terms, postings, proximity_weight)
+        })
+        .fold(0.0, f64::max)
+}
+
+// Rewards articles where the query terms appear close together. The boost shrinks with the number
+// of other tokens in the smallest window containing every term that occurs in the article.
+fn calculate_proximity_boost(
+    article_id: usize,
+    proximity_terms: &[Vec<usize>],
+    postings: &QueryPostings,
+    proximity_weight: f64,
+) -> f64 {
+    // Without a weight the positions don't need decoding
+    if proximity_weight == 0.0 {
+        return 0.0;
+    }
+    let positions = proximity_terms
+        .iter()
+        .filter_map(|occurrences| postings.positions(occurrences, article_id))
+        .collect::<Vec<Vec<usize>>>();
+    let position_lists = positions
+        .iter()
+        .map(Vec::as_slice)
+        .collect::<Vec<&[usize]>>();
+    if position_lists.len() < 2 {
+        return 0.0;
+    }
+
+    match min_span(&position_lists) {
+        Some(span) => {
+            let gap = (span + 1).saturating_sub(position_lists.len());
+            proximity_weight * (position_lists.len() - 1) as f64 / (1.0 + gap as f64)
+        }
+        None => 0.0,
+    }
+}
+
+// Centers the snippet on the first occurrence of a query phrase in the text, or of a single token
+// if there are no phrases in the text. Articles only matching in their title start at the top.
+fn get_article_snippet(
+    article_id: usize,
+    article_text: &str,
+    scoring: &ScoringContext,
+    snippet_offset: usize,
+) -> Result<String, String> {
+    let article_text = article_text.replace(|c: char| !c.is_ascii(), "");
+
+    let position = scoring
+        .scoring_terms
+        .iter()
+        .filter(|scoring_term| scoring_term.field == Field::Text)
+        .filter_map(|scoring_term| {
+            let position = scoring
+                .postings
+                .get(scoring_term.occurrences?)
+                .positions(article_id)?
+                .first()?;
+            Some((scoring_term.num_tokens, scoring_term.query_freq, *position))
+        })
+        .max_by(|a, b| {
+            (a.0, a.1)
+                .partial_cmp(&(b.0, b.1))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .map_or(0, |(_, _, position)| position);
+    let offset = token_offsets(&article_text)
+        .get(position)
+        .copied()
+        .unwrap_or(0);
+
+    let start = offset.saturating_sub(snippet_offset);
+    let end = if offset + snippet_offset > article_text.len() {
+        article_text.len()
+    } else {
+        offset + snippet_offset
+    };
+    Ok(format!("...{}...", &article_text[start..end]).replace('\n', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{Article, Lcg, Page, RankingParams, BLOCK_SIZE, PROXIMITY_WEIGHT},
+        index_engine::{index_builder::IndexBuilder, snippet_engine},
+        query_engine::scoring::RankingModel,
+    };
+
+    const WORDS: [&str; 8] = [
+        "planet", "comet", "orbit", "galaxy", "nebula", "quasar", "moon", "gravity",
+    ];
+
+    // Articles spread over a few postings blocks, every fifth one a copy of the one before it
+    // under another ID so their scores tie
+    fn articles() -> Vec<Article> {
+        let mut random = Lcg::new(7);
+        let mut articles: Vec<Article> = Vec::new();
+        for i in 0..60 {
+            let id = i * BLOCK_SIZE / 12 + 1;
+            if let Some(previous) = articles.last().filter(|_| i % 5 == 4) {
+                articles.push(Article {
+                    id,
+                    ..previous.clone()
+                });
+                continue;
+            }
+            let title = words(&mut random);
+            let text = format!(
+                "{}. [[{}]] {} [[Category:{}]]",
+                words(&mut random),
+                words(&mut random),
+                words(&mut random),
+                words(&mut random)
+            );
+            articles.push(Article { id, title, text });
+        }
+        articles
+    }
+
+    fn words(random: &mut Lcg) -> String {
+        (0..random.next_u64() as usize % 12 + 2)
+            .map(|_| WORDS[random.next_u64() as usize % WORDS.len()])
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    fn build_index(index_path: &str) {
+        let mut index_builder = IndexBuilder::new(index_path, None).unwrap();
+        for article in articles() {
+            let article = index_builder.build_index(&article);
+            snippet_engine::insert_article(&article, index_path).unwrap();
+        }
+        index_builder.index_anchors();
+        index_builder.write_term_dictionary().unwrap();
+        index_builder.write_field_stats().unwrap();
+        index_builder.write_lsa_model().unwrap();
+        index_builder.write_index_config().unwrap();
+        index_builder.update_all_inv_index_files().unwrap();
+        index_builder.write_postings().unwrap();
+        index_builder.write_docstore().unwrap();
+    }
+
+    // The ranked scores with the number of matching articles, if they were all counted
+    fn ranked_scores(
+        query: &str,
+        reader: &IndexReader,
+        options: &SearchOptions,
+        num_top: Option<usize>,
+    ) -> (Vec<(usize, f64)>, Option<usize>) {
+        let parsed_query = parse_query(query).unwrap();
+        run_query(
+            &parsed_query,
+            reader,
+            &SynonymMap::new(),
+            options,
+            num_top,
+            |QueryRun { ranking, .. }| {
+                Ok((
+                    ranking.scores,
+                    ranking.all_matches_counted.then_some(ranking.num_matches),
+                ))
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn wand_keeps_the_same_top_articles_as_exhaustive_ranking() {
+        let index_path =
+            std::env::temp_dir().join(format!("wiki-search-wand-{}", std::process::id()));
+        let index_path = index_path.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(index_path);
+        build_index(index_path);
+        let reader = IndexReader::open(index_path).unwrap();
+
+        let queries = [
+            "planet",
+            "planet comet",
+            "orbit OR galaxy nebula",
+            "\"planet comet\"",
+            "title:quasar moon",
+            "+gravity -title:orbit",
+            "moon^3 galaxy^0.5",
+            "moon NEAR/2 galaxy",
+            "(planet OR \"comet orbit\"~1) AND NOT title:nebula",
+        ];
+        let ranking_models = [
+            RankingModel::Bm25f,
+            RankingModel::Bm25,
+            RankingModel::VectorSpace,
+            RankingModel::LmDirichlet,
+            RankingModel::Dfr,
+        ];
+        for query in queries {
+            for ranking_model in ranking_models {
+                let options = SearchOptions {
+                    page: Page {
+                        offset: 0,
+                        limit: 10,
+                    },
+                    ranking_model,
+                    ranking_params: RankingParams::default(),
+                    proximity_weight: PROXIMITY_WEIGHT,
+                };
+                let (exhaustive, num_matches) = ranked_scores(query, &reader, &options, None);
+                assert!(!exhaustive.is_empty(), "{query} matches no articles");
+                assert_eq!(num_matches, Some(exhaustive.len()));
+                for num_top in [1, 3, 4, 10, 25] {
+                    let mut expected = exhaustive.clone();
+                    expected.truncate(num_top);
+                    let (scores, num_matches) =
+                        ranked_scores(query, &reader, &options, Some(num_top));
+                    assert_eq!(
+                        scores, expected,
+                        "{query} with {ranking_model:?}, top {num_top}"
+                    );
+                    // Matches are only left uncounted once there are as many as the top articles
+                    assert!(
+                        num_matches.is_none_or(|num_matches| num_matches == exhaustive.len()),
+                        "{query} with {ranking_model:?}, top {num_top}"
+                    );
+                    assert!(num_matches.is_some() || exhaustive.len() >= num_top);
+                }
+            }
+        }
+
+        std::fs::remove_dir_all(index_path).unwrap();
+    }
+
+    #[test]
+    fn phrases_share_their_slop_between_tokens() {
+        let general = [0, 10];
+        let relativity = [2, 13];
+        assert!(phrase_positions(&[&general, &relativity], 0).is_empty());
+        assert_eq!(phrase_positions(&[&general, &relativity], 1), vec![0]);
+        assert_eq!(phrase_positions(&[&general, &relativity], 2), vec![0, 10]);
+
+        // One word between each pair of tokens uses up 2 of the slop
+        let (a, b, c) = ([0], [2], [4]);
+        assert!(phrase_positions(&[&a, &b, &c], 1).is_empty());
+        assert_eq!(phrase_positions(&[&a, &b, &c], 2), vec![0]);
+
+        // Tokens have to be in order
+        assert!(phrase_positions(&[&c, &a], 10).is_empty());
+    }
+
+    #[test]
+    fn phrases_with_repeated_tokens_use_distinct_positions() {
+        // "new new york"
+        let new = [1, 2, 5];
+        let york = [3, 6];
+        assert_eq!(phrase_positions(&[&new, &new, &york], 0), vec![1]);
+        assert_eq!(phrase_positions(&[&new, &york], 0), vec![2, 5]);
+
+        // "to be or not to be"
+        let (to, be, or, not) = ([0, 4], [1, 5], [2], [3]);
+        assert_eq!(
+            phrase_positions(&[&to, &be, &or, &not, &to, &be], 0),
+            vec![0]
+        );
+        assert!(phrase_positions(&[&to, &be, &to, &be], 0).is_empty());
+        assert_eq!(phrase_positions(&[&to, &be, &to, &be], 2), vec![0]);
+    }
+
+    #[test]
+    fn min_span_covers_every_list() {
+        assert_eq!(min_span(&[&[0, 10], &[4, 12], &[7]]), Some(5));
+        assert_eq!(min_span(&[&[5, 9]]), Some(0));
+        assert_eq!(min_span(&[&[1, 20], &[]]), None);
+
+        // A repeated token can be covered by a single position
+        assert_eq!(min_span(&[&[3, 8], &[3, 8]]), Some(0));
+        assert_eq!(min_span(&[&[2, 9], &[9], &[2, 9]]), Some(0));
+    }
+}
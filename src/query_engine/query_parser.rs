@@ -0,0 +1,746 @@
+// Parses queries like `einstein AND (relativity OR gravity) NOT newton` into a Query tree.
+//
+// Quoted phrases like `"general relativity"` only match the words next to each other, in order.
+// A slop can be given with `"foo bar"~2` to allow up to 2 other words in between.
+//
+// `a NEAR/5 b` matches terms or phrases within 5 positions of each other, in any order, and
+// binds tighter than AND. Chaining them requires every operand to be within the same window.
+//
+// Words with `*` (any number of characters) or `?` (a single character) are wildcards matching
+// the exact word forms in the index, e.g. `photo*` or `*graphy`.
+//
+// A word followed by `~n` also matches words up to n edits away (at most 2), e.g. `einstien~1`.
+// A bare `~` allows 2 edits.
+//
+// `+term` requires the term and `-term` excludes it (like NOT). In a group of words next to each
+// other, only the required ones have to match and the rest just add to the score, so
+// `+einstein relativity` matches every article about Einstein, ranking the ones mentioning
+// relativity higher. `term^3` multiplies the term's score by 3, and also works on phrases and
+// parenthesized groups.
+//
+// Terms and phrases can be limited to one field, e.g. `title:einstein` or `title:"new york"`.
+// Otherwise they search every field.
+//
+// Precedence from loosest to tightest is OR, AND, NEAR, NOT. Words next to each other without an
+// operator are OR'ed, like the old bag of words queries, and a NOT inside such a group excludes
+// its operand from the whole group, so `a b NOT c` means `(a OR b) AND NOT c`.
+//
+// Malformed queries fail with a ParseError pointing at the character where things went wrong.
+
+use std::fmt;
+
+use crate::common::{Field, MAX_FUZZY_DISTANCE};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermQuery {
+    pub word: String,
+    pub field: Option<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhraseQuery {
+    pub text: String,
+    pub slop: usize,
+    pub field: Option<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WildcardQuery {
+    pub pattern: String,
+    pub field: Option<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyQuery {
+    pub word: String,
+    pub distance: u8,
+    pub field: Option<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Term(TermQuery),
+    Phrase(PhraseQuery),
+    Wildcard(WildcardQuery),
+    Fuzzy(FuzzyQuery),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    Required(Box<Query>),
+    Boost(Box<Query>, f64),
+    Near(Vec<Query>, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    // Index of the offending character in the query, counted in characters rather than bytes
+    pub position: usize,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        ParseError {
+            message: message.into(),
+            position,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position + 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(PhraseQuery),
+    And,
+    Or,
+    Not,
+    Required,
+    Boost(f64),
+    Near(usize),
+    LeftParen,
+    RightParen,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => format!("\"{word}\""),
+            Token::Phrase(phrase) => format!("phrase \"{}\"", phrase.text),
+            Token::And => "\"AND\"".to_string(),
+            Token::Or => "\"OR\"".to_string(),
+            Token::Not => "\"NOT\"".to_string(),
+            Token::Required => "\"+\"".to_string(),
+            Token::Boost(boost) => format!("\"^{boost}\""),
+            Token::Near(distance) => format!("\"NEAR/{distance}\""),
+            Token::LeftParen => "\"(\"".to_string(),
+            Token::RightParen => "\")\"".to_string(),
+        }
+    }
+}
+
+pub fn parse_query(query: &str) -> Result<Query, ParseError> {
+    let tokens = lex(query)?;
+    if tokens.is_empty() {
+        return Err(ParseError::new("Query is empty", 0));
+    }
+
+    let mut parser = Parser { tokens, pos: 0 };
+    let parsed_query = parser.parse_or()?;
+    match parser.peek_positioned() {
+        None => Ok(parsed_query),
+        Some((Token::RightParen, position)) => {
+            Err(ParseError::new("Unbalanced \")\" in query", *position))
+        }
+        Some((token, position)) => Err(ParseError::new(
+            format!("Unexpected {} in query", token.describe()),
+            *position,
+        )),
+    }
+}
+
+// Every token comes with the position of its first character
+fn lex(query: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut word_start = 0;
+    let mut chars = query.chars().enumerate().peekable();
+
+    while let Some((position, c)) = chars.next() {
+        if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+            // A field name right before a quote scopes the phrase
+            let mut field = None;
+            if c == '"' {
+                if let Some(name) = word.strip_suffix(':') {
+                    field = Field::from_name(name);
+                    if field.is_some() {
+                        word.clear();
+                    }
+                }
+            }
+            if !word.is_empty() {
+                tokens.push((lex_word(std::mem::take(&mut word)), word_start));
+            }
+            match c {
+                '(' => tokens.push((Token::LeftParen, position)),
+                ')' => tokens.push((Token::RightParen, position)),
+                '"' => {
+                    let phrase_start = match field {
+                        Some(field) => position - field.name().len() - 1,
+                        None => position,
+                    };
+                    let mut text = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, c)) => text.push(c),
+                            None => {
+                                return Err(ParseError::new("Unbalanced quote in query", position))
+                            }
+                        }
+                    }
+                    // Nothing would match it, which is more likely a mistake than what was meant
+                    if text.trim().is_empty() {
+                        return Err(ParseError::new("Empty phrase in query", phrase_start));
+                    }
+                    let mut slop = 0;
+                    if let Some((slop_position, _)) = chars.next_if(|(_, c)| *c == '~') {
+                        let mut digits = String::new();
+                        while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                            digits.push(digit);
+                        }
+                        slop = digits.parse::<usize>().map_err(|_| {
+                            ParseError::new(
+                                format!("Expected a slop after phrase \"{text}\"~"),
+                                slop_position,
+                            )
+                        })?;
+                    }
+                    tokens.push((
+                        Token::Phrase(PhraseQuery { text, slop, field }),
+                        phrase_start,
+                    ));
+                }
+                _ => {}
+            }
+        } else if (c == '+' || c == '-')
+            && word.is_empty()
+            && chars.peek().is_some_and(|(_, next)| !next.is_whitespace())
+        {
+            let token = match c {
+                '+' => Token::Required,
+                _ => Token::Not,
+            };
+            tokens.push((token, position));
+        } else if c == '^' {
+            if !word.is_empty() {
+                tokens.push((lex_word(std::mem::take(&mut word)), word_start));
+            }
+            let mut digits = String::new();
+            while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                digits.push(digit);
+            }
+            let boost = digits
+                .parse::<f64>()
+                .map_err(|_| ParseError::new("Expected a boost after \"^\"", position))?;
+            if boost <= 0.0 {
+                return Err(ParseError::new("Boosts must be positive", position));
+            }
+            tokens.push((Token::Boost(boost), position));
+        } else {
+            if word.is_empty() {
+                word_start = position;
+            }
+            word.push(c);
+        }
+    }
+
+    if !word.is_empty() {
+        tokens.push((lex_word(word), word_start));
+    }
+
+    Ok(tokens)
+}
+
+// Operators have to be upper case, so "and" or "not" are still searchable words
+fn lex_word(word: String) -> Token {
+    match word.as_str() {
+        "AND" => Token::And,
+        "OR" => Token::Or,
+        "NOT" => Token::Not,
+        _ => match word
+            .strip_prefix("NEAR/")
+            .and_then(|distance| distance.parse::<usize>().ok())
+        {
+            Some(distance) => Token::Near(distance),
+            None => Token::Word(word),
+        },
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.peek_positioned().map(|(token, _)| token)
+    }
+
+    fn peek_positioned(&self) -> Option<&(Token, usize)> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // Position of the token that was just consumed
+    fn last_position(&self) -> usize {
+        self.tokens
+            .get(
+                self.pos
+                    .saturating_sub(1)
+                    .min(self.tokens.len().saturating_sub(1)),
+            )
+            .map_or(0, |(_, position)| *position)
+    }
+
+    fn parse_or(&mut self) -> Result<Query, ParseError> {
+        let mut operands = vec![self.parse_and()?];
+
+        loop {
+            match self.peek() {
+                None | Some(Token::RightParen) => break,
+                Some(Token::Or) => {
+                    self.next();
+                }
+                // Implicit OR between words next to each other
+                Some(_) => {}
+            }
+            operands.push(self.parse_and()?);
+        }
+
+        // With required operands, the others are only used for scoring, which compiling the OR
+        // takes care of
+        let (excluded, included): (Vec<Query>, Vec<Query>) = operands
+            .into_iter()
+            .partition(|operand| matches!(operand, Query::Not(_)));
+
+        let included = match included.len() {
+            0 => None,
+            1 => included.into_iter().next(),
+            _ => Some(Query::Or(included)),
+        };
+
+        Ok(match (included, excluded.is_empty()) {
+            (Some(included), true) => included,
+            (Some(included), false) => Query::And([vec![included], excluded].concat()),
+            (None, _) if excluded.len() == 1 => excluded.into_iter().next().unwrap(),
+            (None, _) => Query::And(excluded),
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Query, ParseError> {
+        let mut operands = vec![self.parse_near()?];
+
+        while let Some(Token::And) = self.peek() {
+            self.next();
+            operands.push(self.parse_near()?);
+        }
+
+        Ok(match operands.len() {
+            1 => operands.pop().unwrap(),
+            _ => Query::And(operands),
+        })
+    }
+
+    fn parse_near(&mut self) -> Result<Query, ParseError> {
+        let first_operand = self.parse_not()?;
+        let mut operands = vec![first_operand];
+        let mut near_distance = None;
+        let mut near_position = 0;
+
+        while let Some((Token::Near(distance), position)) = self.peek_positioned() {
+            let (distance, position) = (*distance, *position);
+            if near_distance.is_some_and(|near_distance| near_distance != distance) {
+                return Err(ParseError::new(
+                    "Chained NEAR operators need the same distance",
+                    position,
+                ));
+            }
+            if near_distance.is_none() {
+                near_position = position;
+            }
+            near_distance = Some(distance);
+            self.next();
+            operands.push(self.parse_not()?);
+        }
+
+        match near_distance {
+            None => Ok(operands.pop().unwrap()),
+            Some(distance) => {
+                if !operands.iter().all(is_near_operand) {
+                    return Err(ParseError::new(
+                        "NEAR only works between terms, phrases and wildcards",
+                        near_position,
+                    ));
+                }
+                Ok(Query::Near(operands, distance))
+            }
+        }
+    }
+
+    fn parse_not(&mut self) -> Result<Query, ParseError> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Query::Not(Box::new(self.parse_not()?)))
+            }
+            Some(Token::Required) => {
+                self.next();
+                Ok(Query::Required(Box::new(self.parse_not()?)))
+            }
+            _ => self.parse_boost(),
+        }
+    }
+
+    fn parse_boost(&mut self) -> Result<Query, ParseError> {
+        let mut parsed_query = self.parse_primary()?;
+        while let Some(Token::Boost(boost)) = self.peek() {
+            parsed_query = Query::Boost(Box::new(parsed_query), *boost);
+            self.next();
+        }
+        Ok(parsed_query)
+    }
+
+    fn parse_primary(&mut self) -> Result<Query, ParseError> {
+        match self.next() {
+            Some((Token::Word(word), position)) => parse_term(word, position),
+            Some((Token::Phrase(phrase), _)) => Ok(Query::Phrase(phrase)),
+            Some((Token::LeftParen, position)) => {
+                if self.peek().is_none() {
+                    return Err(ParseError::new("Unclosed parenthesis in query", position));
+                }
+                let parsed_query = self.parse_or()?;
+                match self.next() {
+                    Some((Token::RightParen, _)) => Ok(parsed_query),
+                    _ => Err(ParseError::new("Unclosed parenthesis in query", position)),
+                }
+            }
+            Some((Token::RightParen, position)) => {
+                Err(ParseError::new("Unexpected \")\" in query", position))
+            }
+            Some((token, position)) => Err(ParseError::new(
+                format!("Expected a term but found {}", token.describe()),
+                position,
+            )),
+            None => Err(ParseError::new(
+                "Query ends with a dangling operator",
+                self.last_position(),
+            )),
+        }
+    }
+}
+
+fn is_near_operand(query: &Query) -> bool {
+    match query {
+        Query::Term(_) | Query::Phrase(_) | Query::Wildcard(_) | Query::Fuzzy(_) => true,
+        Query::Boost(operand, _) => is_near_operand(operand),
+        _ => false,
+    }
+}
+
+// Words prefixed with a field name, like "title:einstein", only search that field. Anything else
+// that looks like a field name followed by a word is most likely a typo. A colon without a term
+// right after it, like in "Star Wars: A New Hope" or "http://", is just part of the text.
+fn parse_term(word: String, position: usize) -> Result<Query, ParseError> {
+    let (field, word, word_position) = match word.split_once(':') {
+        Some((_, "")) => (None, word, position),
+        Some((name, field_word)) => match Field::from_name(name) {
+            Some(field) => (
+                Some(field),
+                field_word.to_string(),
+                position + name.chars().count() + 1,
+            ),
+            None if !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphabetic())
+                && field_word.starts_with(char::is_alphanumeric) =>
+            {
+                let field_names = Field::ALL
+                    .iter()
+                    .map(|field| field.name())
+                    .collect::<Vec<&str>>()
+                    .join(", ");
+                return Err(ParseError::new(
+                    format!("Unknown field \"{name}\", expected one of {field_names}"),
+                    position,
+                ));
+            }
+            None => (None, word, position),
+        },
+        None => (None, word, position),
+    };
+
+    if let Some((fuzzy_word, distance)) = word.rsplit_once('~') {
+        if !fuzzy_word.is_empty() {
+            let distance = match distance {
+                "" => MAX_FUZZY_DISTANCE,
+                _ => distance
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|distance| *distance <= MAX_FUZZY_DISTANCE)
+                    .ok_or_else(|| {
+                        ParseError::new(
+                            format!(
+                                "Expected an edit distance between 0 and {MAX_FUZZY_DISTANCE} after \"{fuzzy_word}~\""
+                            ),
+                            word_position + fuzzy_word.chars().count() + 1,
+                        )
+                    })?,
+            };
+            return Ok(Query::Fuzzy(FuzzyQuery {
+                word: fuzzy_word.to_string(),
+                distance,
+                field,
+            }));
+        }
+    }
+
+    // A question mark at the end is more likely punctuation than a wildcard
+    if word.contains('*') || word.trim_end_matches('?').contains('?') {
+        return Ok(Query::Wildcard(WildcardQuery {
+            pattern: word,
+            field,
+        }));
+    }
+    Ok(Query::Term(TermQuery { word, field }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(query: &str) -> ParseError {
+        parse_query(query).expect_err("query should fail to parse")
+    }
+
+    fn term(word: &str) -> Query {
+        Query::Term(TermQuery {
+            word: word.to_string(),
+            field: None,
+        })
+    }
+
+    #[test]
+    fn operators_bind_from_or_to_not() {
+        assert_eq!(
+            parse_query("a OR b AND c"),
+            Ok(Query::Or(vec![
+                term("a"),
+                Query::And(vec![term("b"), term("c")])
+            ]))
+        );
+        assert_eq!(
+            parse_query("a AND b NEAR/3 c"),
+            Ok(Query::And(vec![
+                term("a"),
+                Query::Near(vec![term("b"), term("c")], 3)
+            ]))
+        );
+        assert_eq!(
+            parse_query("NOT a AND b"),
+            Ok(Query::And(vec![Query::Not(Box::new(term("a"))), term("b")]))
+        );
+        assert_eq!(
+            parse_query("(a OR b) AND c"),
+            Ok(Query::And(vec![
+                Query::Or(vec![term("a"), term("b")]),
+                term("c")
+            ]))
+        );
+        // A NOT among words next to each other excludes its operand from the whole group
+        assert_eq!(
+            parse_query("a b NOT c"),
+            Ok(Query::And(vec![
+                Query::Or(vec![term("a"), term("b")]),
+                Query::Not(Box::new(term("c")))
+            ]))
+        );
+        // Operators have to be upper case
+        assert_eq!(
+            parse_query("a and b"),
+            Ok(Query::Or(vec![term("a"), term("and"), term("b")]))
+        );
+    }
+
+    #[test]
+    fn near_chains_terms_and_phrases() {
+        assert_eq!(
+            parse_query("einstein NEAR/5 \"general relativity\""),
+            Ok(Query::Near(
+                vec![
+                    term("einstein"),
+                    Query::Phrase(PhraseQuery {
+                        text: "general relativity".to_string(),
+                        slop: 0,
+                        field: None,
+                    })
+                ],
+                5
+            ))
+        );
+        assert_eq!(
+            parse_query("a NEAR/2 b NEAR/2 c^2"),
+            Ok(Query::Near(
+                vec![term("a"), term("b"), Query::Boost(Box::new(term("c")), 2.0)],
+                2
+            ))
+        );
+        // Without a valid distance it's just a word
+        assert_eq!(
+            parse_query("a NEAR b"),
+            Ok(Query::Or(vec![term("a"), term("NEAR"), term("b")]))
+        );
+        assert_eq!(
+            parse_error("a NEAR/2 b NEAR/3 c"),
+            ParseError::new("Chained NEAR operators need the same distance", 11)
+        );
+        assert_eq!(
+            parse_error("(a OR b) NEAR/2 c"),
+            ParseError::new("NEAR only works between terms, phrases and wildcards", 9)
+        );
+    }
+
+    #[test]
+    fn plus_requires_and_minus_excludes() {
+        assert_eq!(
+            parse_query("+einstein -newton relativity"),
+            Ok(Query::And(vec![
+                Query::Or(vec![
+                    Query::Required(Box::new(term("einstein"))),
+                    term("relativity")
+                ]),
+                Query::Not(Box::new(term("newton")))
+            ]))
+        );
+        assert_eq!(
+            parse_query("+einstein^2"),
+            Ok(Query::Required(Box::new(Query::Boost(
+                Box::new(term("einstein")),
+                2.0
+            ))))
+        );
+        assert_eq!(
+            parse_query("-(a OR b)"),
+            Ok(Query::Not(Box::new(Query::Or(vec![term("a"), term("b")]))))
+        );
+        // Only at the start of a word and right before something
+        assert_eq!(
+            parse_query("well-known C++"),
+            Ok(Query::Or(vec![term("well-known"), term("C++")]))
+        );
+        assert_eq!(
+            parse_query("a - b"),
+            Ok(Query::Or(vec![term("a"), term("-"), term("b")]))
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_character() {
+        assert_eq!(
+            parse_error("einstein AND"),
+            ParseError::new("Query ends with a dangling operator", 9)
+        );
+        assert_eq!(
+            parse_error("a AND OR b"),
+            ParseError::new("Expected a term but found \"OR\"", 6)
+        );
+        assert_eq!(
+            parse_error("a) b"),
+            ParseError::new("Unbalanced \")\" in query", 1)
+        );
+        assert_eq!(
+            parse_error("einstein title:\"general"),
+            ParseError::new("Unbalanced quote in query", 15)
+        );
+        assert_eq!(
+            parse_error("\"foo bar\"~x"),
+            ParseError::new("Expected a slop after phrase \"foo bar\"~", 9)
+        );
+        assert_eq!(
+            parse_error("title:einstien~3"),
+            ParseError::new(
+                "Expected an edit distance between 0 and 2 after \"einstien~\"",
+                15
+            )
+        );
+        assert_eq!(parse_error("  "), ParseError::new("Query is empty", 0));
+    }
+
+    #[test]
+    fn empty_phrases_are_rejected() {
+        assert_eq!(
+            parse_error("einstein \"\""),
+            ParseError::new("Empty phrase in query", 9)
+        );
+        assert_eq!(
+            parse_error("title:\"  \"~2"),
+            ParseError::new("Empty phrase in query", 0)
+        );
+    }
+
+    #[test]
+    fn unclosed_parentheses_point_at_the_parenthesis() {
+        assert_eq!(
+            parse_error("("),
+            ParseError::new("Unclosed parenthesis in query", 0)
+        );
+        assert_eq!(
+            parse_error("a (b OR c"),
+            ParseError::new("Unclosed parenthesis in query", 2)
+        );
+    }
+
+    #[test]
+    fn positions_count_characters() {
+        assert_eq!(parse_error("café AND )").position, 9);
+        assert_eq!(parse_error("naïve foo:bar").position, 6);
+        assert_eq!(parse_error("über \"\"").position, 5);
+    }
+
+    #[test]
+    fn colons_without_a_term_after_them_are_text() {
+        assert_eq!(
+            parse_query("Star Wars: A New Hope"),
+            Ok(Query::Or(vec![
+                term("Star"),
+                term("Wars:"),
+                term("A"),
+                term("New"),
+                term("Hope")
+            ]))
+        );
+        assert_eq!(
+            parse_query("http://example.org"),
+            Ok(term("http://example.org"))
+        );
+        assert_eq!(parse_query("title:"), Ok(term("title:")));
+        assert_eq!(
+            parse_query("title:Dune"),
+            Ok(Query::Term(TermQuery {
+                word: "Dune".to_string(),
+                field: Some(Field::Title),
+            }))
+        );
+        assert_eq!(
+            parse_error("Re:Zero").message.split(',').next(),
+            Some("Unknown field \"Re\"")
+        );
+    }
+
+    #[test]
+    fn boosts_must_be_positive() {
+        assert_eq!(
+            parse_error("relativity einstein^0"),
+            ParseError::new("Boosts must be positive", 19)
+        );
+        assert_eq!(
+            parse_error("\"general relativity\"^0.0"),
+            ParseError::new("Boosts must be positive", 20)
+        );
+        assert_eq!(
+            parse_error("einstein^"),
+            ParseError::new("Expected a boost after \"^\"", 8)
+        );
+        assert!(parse_query("einstein^0.5").is_ok());
+    }
+}
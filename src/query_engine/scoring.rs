@@ -0,0 +1,439 @@
+use std::fmt;
+
+use crate::common::{Field, IndexConfig, DFR_C, DIRICHLET_MU};
+
+// What a ranking model knows about a term (a single token or a phrase) in an article's field
+pub struct TermStats {
+    pub field: Field,
+    // Occurrences in the article's field
+    pub frequency: f64,
+    pub query_freq: f64,
+    // Number of articles containing the term in this field and in any field, and its occurrences
+    // across all of them in this field
+    pub doc_freq: f64,
+    pub any_field_doc_freq: f64,
+    pub collection_freq: f64,
+    pub field_length: f64,
+    pub average_field_length: f64,
+    // Length of the article's log-scaled term frequency vector in the field
+    pub field_norm: f64,
+    // From the index config
+    pub field_weight: f64,
+    pub b: f64,
+    pub num_articles: f64,
+}
+
+pub trait Scorer {
+    // Score of a term occurring in an article's field
+    fn score_term(&self, stats: &TermStats) -> f64;
+
+    // Score of a term across every field of an article it occurs in. Most models score each field
+    // on its own and add up the weighted field scores.
+    fn score_fields(&self, fields: &[TermStats]) -> f64 {
+        fields
+            .iter()
+            .map(|stats| stats.field_weight * self.score_term(stats))
+            .sum()
+    }
+
+    // Added once per searched field for models where query terms missing from an article affect
+    // its score. `query_length` is the sum of the query frequencies of the field's terms.
+    fn score_field(&self, _field_length: f64, _query_length: f64) -> f64 {
+        0.0
+    }
+
+    // How score_term's score was computed
+    fn explain_term(&self, stats: &TermStats) -> Explanation;
+
+    // How score_fields's score was computed
+    fn explain_fields(&self, fields: &[TermStats]) -> Explanation {
+        let details = fields
+            .iter()
+            .map(|stats| {
+                let term = self.explain_term(stats);
+                Explanation::new(
+                    stats.field_weight * term.value,
+                    format!(
+                        "{} field, weight {}, product of:",
+                        stats.field.name(),
+                        stats.field_weight
+                    ),
+                )
+                .with_details(vec![term])
+            })
+            .collect::<Vec<Explanation>>();
+        Explanation::new(
+            details.iter().map(|detail| detail.value).sum(),
+            "sum of the fields:",
+        )
+        .with_details(details)
+    }
+}
+
+// A score along with how it was computed, for debugging rankings
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub value: f64,
+    pub description: String,
+    pub details: Vec<Explanation>,
+}
+
+impl Explanation {
+    pub fn new(value: f64, description: impl Into<String>) -> Self {
+        Explanation {
+            value,
+            description: description.into(),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn with_details(mut self, details: Vec<Explanation>) -> Self {
+        self.details = details;
+        self
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(
+            f,
+            "{}{} = {}",
+            "  ".repeat(depth),
+            self.value,
+            self.description
+        )?;
+        for detail in &self.details {
+            detail.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RankingModel {
+    Bm25f,
+    Bm25,
+    VectorSpace,
+    LmDirichlet,
+    Dfr,
+}
+
+impl RankingModel {
+    pub const ALL: [RankingModel; 5] = [
+        RankingModel::Bm25f,
+        RankingModel::Bm25,
+        RankingModel::VectorSpace,
+        RankingModel::LmDirichlet,
+        RankingModel::Dfr,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RankingModel::Bm25f => "bm25f",
+            RankingModel::Bm25 => "bm25",
+            RankingModel::VectorSpace => "tfidf",
+            RankingModel::LmDirichlet => "lm-dirichlet",
+            RankingModel::Dfr => "dfr",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RankingModel> {
+        RankingModel::ALL
+            .into_iter()
+            .find(|ranking_model| ranking_model.name() == name)
+    }
+
+    pub fn scorer(&self, index_config: &IndexConfig) -> Box<dyn Scorer> {
+        let (k1, k2) = (index_config.k1, index_config.k2);
+        match self {
+            RankingModel::Bm25f => Box::new(Bm25f { k1, k2 }),
+            RankingModel::Bm25 => Box::new(Bm25 { k1, k2 }),
+            RankingModel::VectorSpace => Box::new(VectorSpace),
+            RankingModel::LmDirichlet => Box::new(LmDirichlet { mu: DIRICHLET_MU }),
+            RankingModel::Dfr => Box::new(Dfr { c: DFR_C }),
+        }
+    }
+}
+
+// BM25F: the length normalized term frequencies of every field are weighted and added up before
+// saturating, so a term occurring in several fields isn't counted as several independent terms
+pub struct Bm25f {
+    pub k1: f64,
+    pub k2: f64,
+}
+
+impl Scorer for Bm25f {
+    fn score_term(&self, stats: &TermStats) -> f64 {
+        // A field weighted 0 doesn't count towards the score, so there's no weight to take out
+        if stats.field_weight == 0.0 {
+            return 0.0;
+        }
+        self.score_fields(std::slice::from_ref(stats)) / stats.field_weight
+    }
+
+    fn score_fields(&self, fields: &[TermStats]) -> f64 {
+        let first = match fields.first() {
+            Some(first) => first,
+            None => return 0.0,
+        };
+        let tf = fields
+            .iter()
+            .map(|stats| {
+                stats.field_weight * stats.frequency
+                    / ((1.0 - stats.b) + stats.b * stats.field_length / stats.average_field_length)
+            })
+            .sum::<f64>();
+        let query_freq = fields
+            .iter()
+            .map(|stats| stats.query_freq)
+            .fold(0.0, f64::max);
+        let qf = (self.k2 + 1.0) * query_freq / (self.k2 + query_freq);
+        let idf = bm25_idf(first.any_field_doc_freq, first.num_articles);
+        (self.k1 + 1.0) * tf / (self.k1 + tf) * qf * idf
+    }
+
+    fn explain_term(&self, stats: &TermStats) -> Explanation {
+        if stats.field_weight == 0.0 {
+            return Explanation::new(
+                0.0,
+                format!(
+                    "BM25F in the {} field, which has weight 0",
+                    stats.field.name()
+                ),
+            );
+        }
+        let fields = self.explain_fields(std::slice::from_ref(stats));
+        Explanation::new(
+            fields.value / stats.field_weight,
+            format!(
+                "BM25F in the {} field without its weight {}, from:",
+                stats.field.name(),
+                stats.field_weight
+            ),
+        )
+        .with_details(vec![fields])
+    }
+
+    fn explain_fields(&self, fields: &[TermStats]) -> Explanation {
+        let first = match fields.first() {
+            Some(first) => first,
+            None => return Explanation::new(0.0, "no matching fields"),
+        };
+        let field_tfs = fields
+            .iter()
+            .map(|stats| {
+                let length_norm = explain_length_norm(stats);
+                Explanation::new(
+                    stats.field_weight * stats.frequency / length_norm.value,
+                    format!(
+                        "{} field, weight {} * tf {} / length norm:",
+                        stats.field.name(),
+                        stats.field_weight,
+                        stats.frequency
+                    ),
+                )
+                .with_details(vec![length_norm])
+            })
+            .collect::<Vec<Explanation>>();
+        let tf = field_tfs.iter().map(|field_tf| field_tf.value).sum::<f64>();
+        let tf = Explanation::new(
+            (self.k1 + 1.0) * tf / (self.k1 + tf),
+            format!(
+                "tf, (k1 + 1) * tf / (k1 + tf) with k1 {} and the weighted, length normalized tf {} of:",
+                self.k1, tf
+            ),
+        )
+        .with_details(field_tfs);
+        let query_freq = fields
+            .iter()
+            .map(|stats| stats.query_freq)
+            .fold(0.0, f64::max);
+        let qf = explain_qf(self.k2, query_freq);
+        let idf = explain_bm25_idf(first.any_field_doc_freq, first.num_articles);
+        Explanation::new(tf.value * qf.value * idf.value, "BM25F, product of:")
+            .with_details(vec![tf, qf, idf])
+    }
+}
+
+// Okapi BM25 within each field, with K2 saturating repeated query terms
+pub struct Bm25 {
+    pub k1: f64,
+    pub k2: f64,
+}
+
+impl Scorer for Bm25 {
+    fn score_term(&self, stats: &TermStats) -> f64 {
+        let k =
+            self.k1 * ((1.0 - stats.b) + stats.b * stats.field_length / stats.average_field_length);
+        let tf = (self.k1 + 1.0) * stats.frequency / (k + stats.frequency);
+        let qf = (self.k2 + 1.0) * stats.query_freq / (self.k2 + stats.query_freq);
+        tf * qf * bm25_idf(stats.doc_freq, stats.num_articles)
+    }
+
+    fn explain_term(&self, stats: &TermStats) -> Explanation {
+        let length_norm = explain_length_norm(stats);
+        let k = self.k1 * length_norm.value;
+        let tf = Explanation::new(
+            (self.k1 + 1.0) * stats.frequency / (k + stats.frequency),
+            format!(
+                "tf, (k1 + 1) * tf / (k1 * length norm + tf) with k1 {} and tf {}:",
+                self.k1, stats.frequency
+            ),
+        )
+        .with_details(vec![length_norm]);
+        let qf = explain_qf(self.k2, stats.query_freq);
+        let idf = explain_bm25_idf(stats.doc_freq, stats.num_articles);
+        Explanation::new(tf.value * qf.value * idf.value, "BM25, product of:")
+            .with_details(vec![tf, qf, idf])
+    }
+}
+
+fn bm25_idf(doc_freq: f64, num_articles: f64) -> f64 {
+    ((num_articles - doc_freq + 0.5) / (doc_freq + 0.5) + 1.0).ln()
+}
+
+fn explain_bm25_idf(doc_freq: f64, num_articles: f64) -> Explanation {
+    Explanation::new(
+        bm25_idf(doc_freq, num_articles),
+        format!("idf, ln((N - df + 0.5) / (df + 0.5) + 1) with df {doc_freq} and N {num_articles}"),
+    )
+}
+
+fn explain_length_norm(stats: &TermStats) -> Explanation {
+    Explanation::new(
+        (1.0 - stats.b) + stats.b * stats.field_length / stats.average_field_length,
+        format!(
+            "length norm, (1 - b) + b * length / average length with b {}, length {} and average length {}",
+            stats.b, stats.field_length, stats.average_field_length
+        ),
+    )
+}
+
+fn explain_qf(k2: f64, query_freq: f64) -> Explanation {
+    Explanation::new(
+        (k2 + 1.0) * query_freq / (k2 + query_freq),
+        format!("qf, (k2 + 1) * qf / (k2 + qf) with k2 {k2} and qf {query_freq}"),
+    )
+}
+
+// Cosine similarity between log-scaled term frequencies and IDF weighted query terms (SMART
+// lnc.ltc). The query vector isn't normalized since that doesn't change the ranking.
+pub struct VectorSpace;
+
+impl Scorer for VectorSpace {
+    fn score_term(&self, stats: &TermStats) -> f64 {
+        if stats.field_norm == 0.0 {
+            return 0.0;
+        }
+        let tf = (1.0 + stats.frequency.ln()) / stats.field_norm;
+        let idf = (stats.num_articles / stats.doc_freq).ln();
+        tf * stats.query_freq * idf
+    }
+
+    fn explain_term(&self, stats: &TermStats) -> Explanation {
+        if stats.field_norm == 0.0 {
+            return Explanation::new(0.0, "empty field");
+        }
+        let tf = Explanation::new(
+            (1.0 + stats.frequency.ln()) / stats.field_norm,
+            format!(
+                "tf, (1 + ln(tf)) / field norm with tf {} and field norm {}",
+                stats.frequency, stats.field_norm
+            ),
+        );
+        let qf = Explanation::new(stats.query_freq, "qf");
+        let idf = Explanation::new(
+            (stats.num_articles / stats.doc_freq).ln(),
+            format!(
+                "idf, ln(N / df) with df {} and N {}",
+                stats.doc_freq, stats.num_articles
+            ),
+        );
+        Explanation::new(tf.value * qf.value * idf.value, "TF-IDF, product of:")
+            .with_details(vec![tf, qf, idf])
+    }
+}
+
+// Query likelihood with Dirichlet smoothing. Longer articles are penalized through `score_field`
+// since they're less likely to generate any given query.
+pub struct LmDirichlet {
+    pub mu: f64,
+}
+
+impl Scorer for LmDirichlet {
+    fn score_term(&self, stats: &TermStats) -> f64 {
+        let collection_length = stats.average_field_length * stats.num_articles;
+        let collection_prob = stats.collection_freq / collection_length;
+        stats.query_freq * (1.0 + stats.frequency / (self.mu * collection_prob)).ln()
+    }
+
+    fn explain_term(&self, stats: &TermStats) -> Explanation {
+        let collection_length = stats.average_field_length * stats.num_articles;
+        let collection_prob = Explanation::new(
+            stats.collection_freq / collection_length,
+            format!(
+                "collection probability, collection frequency {} / collection length {}",
+                stats.collection_freq, collection_length
+            ),
+        );
+        let smoothed_tf = Explanation::new(
+            (1.0 + stats.frequency / (self.mu * collection_prob.value)).ln(),
+            format!(
+                "ln(1 + tf / (mu * collection probability)) with tf {} and mu {}:",
+                stats.frequency, self.mu
+            ),
+        )
+        .with_details(vec![collection_prob]);
+        let qf = Explanation::new(stats.query_freq, "qf");
+        Explanation::new(
+            qf.value * smoothed_tf.value,
+            "Dirichlet smoothed language model, product of:",
+        )
+        .with_details(vec![qf, smoothed_tf])
+    }
+
+    fn score_field(&self, field_length: f64, query_length: f64) -> f64 {
+        query_length * (self.mu / (field_length + self.mu)).ln()
+    }
+}
+
+// Divergence from randomness, using the InL2 model: inverse document frequency with a Laplace
+// after-effect and the term frequency normalized by the field length (normalization 2)
+pub struct Dfr {
+    pub c: f64,
+}
+
+impl Scorer for Dfr {
+    fn score_term(&self, stats: &TermStats) -> f64 {
+        let tfn = stats.frequency
+            * (1.0 + self.c * stats.average_field_length / stats.field_length.max(1.0)).log2();
+        let idf = ((stats.num_articles + 1.0) / (stats.doc_freq + 0.5)).log2();
+        stats.query_freq * tfn / (tfn + 1.0) * idf
+    }
+
+    fn explain_term(&self, stats: &TermStats) -> Explanation {
+        let tfn = stats.frequency
+            * (1.0 + self.c * stats.average_field_length / stats.field_length.max(1.0)).log2();
+        let tf = Explanation::new(
+            tfn / (tfn + 1.0),
+            format!(
+                "tf, tfn / (tfn + 1) with tfn = tf * log2(1 + c * average length / length) = {} for tf {}, c {}, length {} and average length {}",
+                tfn, stats.frequency, self.c, stats.field_length, stats.average_field_length
+            ),
+        );
+        let qf = Explanation::new(stats.query_freq, "qf");
+        let idf = Explanation::new(
+            ((stats.num_articles + 1.0) / (stats.doc_freq + 0.5)).log2(),
+            format!(
+                "idf, log2((N + 1) / (df + 0.5)) with df {} and N {}",
+                stats.doc_freq, stats.num_articles
+            ),
+        );
+        Explanation::new(qf.value * tf.value * idf.value, "DFR InL2, product of:")
+            .with_details(vec![qf, tf, idf])
+    }
+}
@@ -0,0 +1,125 @@
+use super::{query_parser::Query, term_dictionary::TermDictionary};
+use crate::common::{
+    tokenize, tokenize_exact, EXACT_TOKEN_MARKER, MAX_FUZZY_DISTANCE, MAX_SUGGESTION_CANDIDATES,
+    SUGGESTION_MAX_HITS,
+};
+
+// Typos are more likely in longer words, so they're allowed more edits
+pub fn auto_fuzzy_distance(token: &str) -> u8 {
+    match token.trim_start_matches(EXACT_TOKEN_MARKER).len() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => MAX_FUZZY_DISTANCE,
+    }
+}
+
+// Rewrites the query with every misspelled word replaced by the closest, most common word in the
+// index. Words that aren't in the index are always corrected, while known words are only replaced
+// by more common ones when the query has few hits, which it doesn't if `num_hits` is None because
+// they weren't all counted. Returns None if nothing was corrected, or if the suggestion is the
+// query itself.
+pub fn suggest_query(
+    query: &str,
+    parsed_query: &Query,
+    term_dictionary: &TermDictionary,
+    num_hits: Option<usize>,
+) -> Option<String> {
+    let mut words = Vec::new();
+    collect_words(parsed_query, &mut words);
+
+    let mut suggestion = query.to_string();
+    let mut corrected = false;
+    for word in words {
+        // Exact words are written that way on purpose
+        if word.starts_with(EXACT_TOKEN_MARKER) {
+            continue;
+        }
+        let (exact_word, stem) = match (tokenize_exact(word).as_slice(), tokenize(word).as_slice())
+        {
+            ([exact_word], [stem]) => (exact_word.clone(), stem.clone()),
+            _ => continue,
+        };
+        let known = term_dictionary.get(&stem).is_some();
+        if known && num_hits.is_none_or(|num_hits| num_hits > SUGGESTION_MAX_HITS) {
+            continue;
+        }
+
+        // Exact tokens already carry the marker, which doesn't count towards the edits allowed
+        let original_doc_freq = word_doc_freq(&exact_word, term_dictionary);
+        let bare_word = exact_word.trim_start_matches(EXACT_TOKEN_MARKER);
+        let correction = term_dictionary
+            .expand_fuzzy(
+                &exact_word,
+                auto_fuzzy_distance(bare_word),
+                MAX_SUGGESTION_CANDIDATES,
+            )
+            .into_iter()
+            .find(|(candidate, distance)| {
+                *distance > 0 && word_doc_freq(candidate, term_dictionary) > original_doc_freq
+            });
+        if let Some((correction, _)) = correction {
+            let correction = correction.trim_start_matches(EXACT_TOKEN_MARKER);
+            let original = word.trim_matches(|c: char| !c.is_alphanumeric());
+            if let Some(replaced) = replace_word(&suggestion, original, correction) {
+                suggestion = replaced;
+                corrected = true;
+            }
+        }
+    }
+
+    // Replacing a word by itself, e.g. in another case, isn't a correction
+    corrected
+        .then_some(suggestion)
+        .filter(|suggestion| suggestion != query)
+}
+
+fn word_doc_freq(token: &str, term_dictionary: &TermDictionary) -> usize {
+    term_dictionary
+        .get(token)
+        .map(|token_id| term_dictionary.doc_freq(token_id))
+        .unwrap_or(0)
+}
+
+// Plain words only, corrections for phrases, wildcards and fuzzy terms are more likely to be wrong.
+// Excluded words are skipped too since correcting them can't bring up more results.
+fn collect_words<'a>(query: &'a Query, words: &mut Vec<&'a str>) {
+    match query {
+        Query::Term(term) => words.push(&term.word),
+        Query::And(operands) | Query::Or(operands) | Query::Near(operands, _) => {
+            for operand in operands {
+                collect_words(operand, words);
+            }
+        }
+        Query::Required(operand) | Query::Boost(operand, _) => collect_words(operand, words),
+        Query::Not(_) | Query::Phrase(_) | Query::Wildcard(_) | Query::Fuzzy(_) => {}
+    }
+}
+
+// Replaces the first whole-word occurrence of `word`, keeping a leading capital letter
+fn replace_word(text: &str, word: &str, replacement: &str) -> Option<String> {
+    if word.is_empty() {
+        return None;
+    }
+    let is_boundary = |c: Option<char>| c.is_none_or(|c| !c.is_alphanumeric());
+    let start = text
+        .match_indices(word)
+        .map(|(start, _)| start)
+        .find(|start| {
+            is_boundary(text[..*start].chars().next_back())
+                && is_boundary(text[start + word.len()..].chars().next())
+        })?;
+
+    let mut replacement = replacement.to_string();
+    if word.starts_with(|c: char| c.is_uppercase()) {
+        if let Some(first) = replacement.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+    }
+
+    Some(format!(
+        "{}{}{}",
+        &text[..start],
+        replacement,
+        &text[start + word.len()..]
+    ))
+}
@@ -0,0 +1,284 @@
+use std::{collections::HashMap, fmt};
+
+use crate::common::{tokenize, SYNONYM_WEIGHT};
+
+// A query term (possibly spanning multiple tokens) along with everything it expands to
+#[derive(Debug, Clone)]
+pub struct ExpandedTerm {
+    pub tokens: Vec<String>,
+    pub alternatives: Vec<Alternative>,
+}
+
+// Alternatives with several tokens are matched as phrases, allowing up to `slop` other tokens in between
+#[derive(Debug, Clone)]
+pub struct Alternative {
+    pub tokens: Vec<String>,
+    pub weight: f64,
+    pub slop: usize,
+}
+
+impl fmt::Display for Alternative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tokens.as_slice() {
+            [token] => write!(f, "{token}")?,
+            tokens => write!(f, "\"{}\"", tokens.join(" "))?,
+        }
+        if self.slop > 0 {
+            write!(f, "~{}", self.slop)?;
+        }
+        write!(f, "^{}", self.weight)
+    }
+}
+
+// Synonyms in the Solr format:
+//   ww2, world war ii, second world war   <- equivalent, each one expands to all of them
+//   usa, us => united states              <- explicit, the left side is replaced by the right side
+// Entries are stemmed with the same tokenizer as queries so "Universities" matches "university"
+#[derive(Debug, Default)]
+pub struct SynonymMap {
+    entries: HashMap<Vec<String>, Vec<Vec<String>>>,
+    max_entry_len: usize,
+}
+
+impl SynonymMap {
+    pub fn new() -> Self {
+        SynonymMap::default()
+    }
+
+    pub fn load(synonyms_path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(synonyms_path)
+            .map_err(|e| format!("Failed to read synonyms file: {e}"))?;
+        SynonymMap::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut synonym_map = SynonymMap::new();
+
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment_start) => &line[..comment_start],
+                None => line,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match line.split_once("=>") {
+                Some((lhs, rhs)) => {
+                    let sources = parse_synonym_list(lhs);
+                    let targets = parse_synonym_list(rhs);
+                    if sources.is_empty() || targets.is_empty() {
+                        return Err(format!(
+                            "Failed to parse synonyms file at line {}: both sides of \"=>\" need at least one term",
+                            line_num + 1
+                        ));
+                    }
+                    for source in sources {
+                        synonym_map.insert(source, &targets);
+                    }
+                }
+                None => {
+                    let synonyms = parse_synonym_list(line);
+                    for synonym in &synonyms {
+                        synonym_map.insert(synonym.clone(), &synonyms);
+                    }
+                }
+            }
+        }
+
+        Ok(synonym_map)
+    }
+
+    // Walks the query tokens left to right, greedily matching the longest synonym entry at each position
+    pub fn expand(&self, tokens: &[String]) -> Vec<ExpandedTerm> {
+        let mut expanded_terms = Vec::new();
+        let mut start = 0;
+
+        while start < tokens.len() {
+            let longest_match = (1..=self.max_entry_len.min(tokens.len() - start))
+                .rev()
+                .find_map(|len| {
+                    self.entries
+                        .get(&tokens[start..start + len])
+                        .map(|targets| (len, targets))
+                });
+
+            match longest_match {
+                Some((len, targets)) => {
+                    let source = tokens[start..start + len].to_vec();
+                    let alternatives = targets
+                        .iter()
+                        .map(|target| Alternative {
+                            weight: if *target == source {
+                                1.0
+                            } else {
+                                SYNONYM_WEIGHT
+                            },
+                            tokens: target.clone(),
+                            slop: 0,
+                        })
+                        .collect();
+                    expanded_terms.push(ExpandedTerm {
+                        tokens: source,
+                        alternatives,
+                    });
+                    start += len;
+                }
+                None => {
+                    let source = vec![tokens[start].clone()];
+                    expanded_terms.push(ExpandedTerm {
+                        alternatives: vec![Alternative {
+                            tokens: source.clone(),
+                            weight: 1.0,
+                            slop: 0,
+                        }],
+                        tokens: source,
+                    });
+                    start += 1;
+                }
+            }
+        }
+
+        expanded_terms
+    }
+
+    fn insert(&mut self, source: Vec<String>, targets: &[Vec<String>]) {
+        self.max_entry_len = self.max_entry_len.max(source.len());
+        let entry = self.entries.entry(source).or_default();
+        for target in targets {
+            if !entry.contains(target) {
+                entry.push(target.clone());
+            }
+        }
+    }
+}
+
+fn parse_synonym_list(list: &str) -> Vec<Vec<String>> {
+    list.split(',')
+        .map(tokenize)
+        .filter(|tokens| !tokens.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stem(text: &str) -> String {
+        tokenize(text).join(" ")
+    }
+
+    // Each expanded term's tokens, with its alternatives and their weights
+    fn expand(synonym_map: &SynonymMap, query: &str) -> Vec<(String, Vec<(String, f64)>)> {
+        synonym_map
+            .expand(&tokenize(query))
+            .into_iter()
+            .map(|expanded_term| {
+                let alternatives = expanded_term
+                    .alternatives
+                    .iter()
+                    .map(|alternative| (alternative.tokens.join(" "), alternative.weight))
+                    .collect();
+                (expanded_term.tokens.join(" "), alternatives)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn equivalent_synonyms_expand_to_each_other() {
+        let synonym_map = SynonymMap::parse("ww2, world war ii, second world war").unwrap();
+        let alternatives = vec![
+            (stem("ww2"), 1.0),
+            (stem("world war ii"), SYNONYM_WEIGHT),
+            (stem("second world war"), SYNONYM_WEIGHT),
+        ];
+
+        assert_eq!(
+            expand(&synonym_map, "WW2"),
+            vec![(stem("ww2"), alternatives.clone())]
+        );
+        let second_world_war = expand(&synonym_map, "second world war");
+        assert_eq!(second_world_war.len(), 1);
+        assert_eq!(second_world_war[0].0, stem("second world war"));
+        assert_eq!(second_world_war[0].1.len(), 3);
+        assert!(second_world_war[0]
+            .1
+            .contains(&(stem("second world war"), 1.0)));
+        assert!(second_world_war[0]
+            .1
+            .contains(&(stem("ww2"), SYNONYM_WEIGHT)));
+    }
+
+    #[test]
+    fn explicit_mappings_only_go_one_way() {
+        let synonym_map = SynonymMap::parse("usa, us => united states").unwrap();
+
+        for query in ["usa", "US"] {
+            assert_eq!(
+                expand(&synonym_map, query),
+                vec![(stem(query), vec![(stem("united states"), SYNONYM_WEIGHT)])]
+            );
+        }
+        assert_eq!(
+            expand(&synonym_map, "united states"),
+            vec![
+                (stem("united"), vec![(stem("united"), 1.0)]),
+                (stem("states"), vec![(stem("states"), 1.0)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_word_synonyms_match_the_longest_entry() {
+        let synonym_map =
+            SynonymMap::parse("new york, nyc\nnew york city, big apple\nlondon, big smoke")
+                .unwrap();
+
+        let expanded_terms = expand(&synonym_map, "new york city marathon");
+        assert_eq!(
+            expanded_terms[0],
+            (
+                stem("new york city"),
+                vec![
+                    (stem("new york city"), 1.0),
+                    (stem("big apple"), SYNONYM_WEIGHT)
+                ]
+            )
+        );
+        assert_eq!(
+            expanded_terms[1],
+            (stem("marathon"), vec![(stem("marathon"), 1.0)])
+        );
+        assert_eq!(expanded_terms.len(), 2);
+
+        let expanded_terms = expand(&synonym_map, "new york london");
+        assert_eq!(expanded_terms.len(), 2);
+        assert_eq!(expanded_terms[0].0, stem("new york"));
+        assert_eq!(expanded_terms[1].0, stem("london"));
+    }
+
+    #[test]
+    fn entries_for_the_same_term_are_merged() {
+        let synonym_map =
+            SynonymMap::parse("# Vehicles\n\ncar, automobile  # common\ncar => vehicle\n").unwrap();
+
+        assert_eq!(
+            expand(&synonym_map, "car"),
+            vec![(
+                stem("car"),
+                vec![
+                    (stem("car"), 1.0),
+                    (stem("automobile"), SYNONYM_WEIGHT),
+                    (stem("vehicle"), SYNONYM_WEIGHT)
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn mappings_need_terms_on_both_sides() {
+        let error = SynonymMap::parse("car, automobile\n => vehicle").unwrap_err();
+        assert!(error.contains("line 2"), "{error}");
+        assert!(SynonymMap::parse("car =>").is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{cmp::Reverse, collections::BinaryHeap, path::Path};
+
+use fst::{Automaton, IntoStreamer, Map, Streamer};
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder};
+use memmap2::Mmap;
+
+use crate::{
+    common::{EXACT_TOKEN_MARKER, MAX_WILDCARD_SCANNED_TERMS},
+    index_files::{map_file, Column},
+};
+
+// The sorted term dictionary written at build time. Exact tokens share the dictionary with the
+// stemmed ones, marked with EXACT_TOKEN_MARKER. Both files are memory-mapped.
+pub struct TermDictionary {
+    terms: Map<Mmap>,
+    // Token ID -> number of articles it appears in
+    doc_freqs: Column<u32>,
+}
+
+impl TermDictionary {
+    pub fn load(index_path: &Path) -> Result<Self, String> {
+        let terms = Map::new(map_file(&index_path.join("terms.fst"))?)
+            .map_err(|e| format!("Failed to parse terms.fst file: {e}"))?;
+
+        let doc_freqs = Column::open(&index_path.join("doc_freqs.bin"))?;
+
+        Ok(TermDictionary { terms, doc_freqs })
+    }
+
+    pub fn get(&self, token: &str) -> Option<usize> {
+        self.terms.get(token).map(|token_id| token_id as usize)
+    }
+
+    // Number of articles the token appears in
+    pub fn doc_freq(&self, token_id: usize) -> usize {
+        self.doc_freqs.get(token_id).unwrap_or(0) as usize
+    }
+
+    // Tokens matching a pattern where `*` matches any number of characters and `?` matches exactly
+    // one. Only the `max_expansions` tokens appearing in the most articles are kept, out of the
+    // first MAX_WILDCARD_SCANNED_TERMS matches in dictionary order.
+    pub fn expand_wildcard(&self, pattern: &str, max_expansions: usize) -> Vec<String> {
+        let mut stream = self
+            .terms
+            .search(WildcardAutomaton::new(pattern))
+            .into_stream();
+        // Min-heap holding the best expansions so far, with the one to drop first on top. More
+        // common tokens, then alphabetically earlier ones, are better.
+        let mut expansions = BinaryHeap::with_capacity(max_expansions + 1);
+        let mut num_scanned_terms = 0;
+        while let Some((token, token_id)) = stream.next() {
+            expansions.push(Reverse((
+                self.doc_freq(token_id as usize),
+                Reverse(String::from_utf8_lossy(token).to_string()),
+            )));
+            if expansions.len() > max_expansions {
+                expansions.pop();
+            }
+
+            num_scanned_terms += 1;
+            if num_scanned_terms == MAX_WILDCARD_SCANNED_TERMS {
+                break;
+            }
+        }
+
+        expansions
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((_, Reverse(token)))| token)
+            .collect()
+    }
+
+    // Tokens within `max_distance` edits of the given one, along with their distance. Swapping two
+    // adjacent characters counts as a single edit. Exact tokens only match exact tokens and stems
+    // only match stems. The closest and most common tokens come first.
+    pub fn expand_fuzzy(
+        &self,
+        token: &str,
+        max_distance: u8,
+        max_expansions: usize,
+    ) -> Vec<(String, u8)> {
+        let dfa = LevenshteinAutomatonBuilder::new(max_distance, true).build_dfa(token);
+        let mut stream = self.terms.search(&dfa).into_stream();
+        let is_exact = token.starts_with(EXACT_TOKEN_MARKER);
+        let mut expansions = Vec::new();
+        while let Some((matched_token, token_id)) = stream.next() {
+            if matched_token.starts_with(&[EXACT_TOKEN_MARKER as u8]) != is_exact {
+                continue;
+            }
+            if let Distance::Exact(distance) = dfa.eval(matched_token) {
+                expansions.push((
+                    distance,
+                    self.doc_freq(token_id as usize),
+                    String::from_utf8_lossy(matched_token).to_string(),
+                ));
+            }
+        }
+
+        expansions.sort_unstable_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then_with(|| b.1.cmp(&a.1))
+                .then_with(|| a.2.cmp(&b.2))
+        });
+        expansions
+            .into_iter()
+            .take(max_expansions)
+            .map(|(distance, _, token)| (token, distance))
+            .collect()
+    }
+}
+
+// Matches tokens against a wildcard pattern by tracking the set of pattern positions reached so
+// far, which lets the FST skip every branch where that set becomes empty
+struct WildcardAutomaton {
+    pattern: Vec<u8>,
+}
+
+impl WildcardAutomaton {
+    fn new(pattern: &str) -> Self {
+        WildcardAutomaton {
+            pattern: pattern.as_bytes().to_vec(),
+        }
+    }
+
+    // Stars can match nothing, so reaching one also reaches the position after it
+    fn close(&self, mut positions: Vec<bool>) -> Vec<bool> {
+        for i in 0..self.pattern.len() {
+            if positions[i] && self.pattern[i] == b'*' {
+                positions[i + 1] = true;
+            }
+        }
+        positions
+    }
+}
+
+impl Automaton for WildcardAutomaton {
+    type State = Vec<bool>;
+
+    fn start(&self) -> Vec<bool> {
+        let mut positions = vec![false; self.pattern.len() + 1];
+        positions[0] = true;
+        self.close(positions)
+    }
+
+    fn is_match(&self, positions: &Vec<bool>) -> bool {
+        positions[self.pattern.len()]
+    }
+
+    fn can_match(&self, positions: &Vec<bool>) -> bool {
+        positions.iter().any(|reached| *reached)
+    }
+
+    fn accept(&self, positions: &Vec<bool>, byte: u8) -> Vec<bool> {
+        let mut next_positions = vec![false; self.pattern.len() + 1];
+        for i in 0..self.pattern.len() {
+            if !positions[i] {
+                continue;
+            }
+            match self.pattern[i] {
+                b'*' => next_positions[i] = true,
+                b'?' => next_positions[i + 1] = true,
+                pattern_byte if pattern_byte == byte => next_positions[i + 1] = true,
+                _ => {}
+            }
+        }
+        self.close(next_positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fst::MapBuilder;
+
+    use super::*;
+
+    // Writes a dictionary of the given tokens and document frequencies, with token IDs in order
+    fn term_dictionary(name: &str, tokens: &[(&str, u32)]) -> TermDictionary {
+        let index_path = std::env::temp_dir().join(format!(
+            "wiki-search-term-dictionary-{name}-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&index_path).unwrap();
+
+        let mut sorted_tokens = tokens
+            .iter()
+            .enumerate()
+            .map(|(token_id, (token, _))| (*token, token_id))
+            .collect::<Vec<(&str, usize)>>();
+        sorted_tokens.sort_unstable();
+        let file = std::fs::File::create(index_path.join("terms.fst")).unwrap();
+        let mut term_dictionary_builder = MapBuilder::new(file).unwrap();
+        for (token, token_id) in sorted_tokens {
+            term_dictionary_builder
+                .insert(token, token_id as u64)
+                .unwrap();
+        }
+        term_dictionary_builder.finish().unwrap();
+        Column::write(
+            &index_path.join("doc_freqs.bin"),
+            tokens.iter().map(|(_, doc_freq)| *doc_freq),
+        )
+        .unwrap();
+
+        let term_dictionary = TermDictionary::load(&index_path).unwrap();
+        std::fs::remove_dir_all(&index_path).unwrap();
+        term_dictionary
+    }
+
+    #[test]
+    fn wildcards_keep_the_most_common_tokens() {
+        let term_dictionary = term_dictionary(
+            "wildcard",
+            &[
+                ("relativ", 3),
+                ("relax", 7),
+                ("relay", 3),
+                ("rely", 9),
+                ("reli", 1),
+                ("gravity", 20),
+            ],
+        );
+
+        assert_eq!(
+            term_dictionary.expand_wildcard("rel*", 3),
+            vec!["rely", "relax", "relativ"]
+        );
+        assert_eq!(
+            term_dictionary.expand_wildcard("rel?", 10),
+            vec!["rely", "reli"]
+        );
+        assert!(term_dictionary.expand_wildcard("rel*", 0).is_empty());
+        assert!(term_dictionary.expand_wildcard("orbit*", 3).is_empty());
+    }
+
+    #[test]
+    fn fuzzy_matches_come_closest_and_most_common_first() {
+        let term_dictionary = term_dictionary(
+            "fuzzy",
+            &[
+                ("einstein", 10),
+                ("einsten", 2),
+                ("einsteins", 2),
+                ("epstein", 5),
+                ("frankenstein", 3),
+                ("=einstein", 4),
+                ("=einstien", 1),
+            ],
+        );
+        let expand_fuzzy = |token: &str, max_distance: u8, max_expansions: usize| {
+            term_dictionary.expand_fuzzy(token, max_distance, max_expansions)
+        };
+        let expansion = |token: &str, distance: u8| (token.to_string(), distance);
+
+        // Swapping "ie" is a single edit
+        assert_eq!(
+            expand_fuzzy("einstien", 2, 10),
+            vec![
+                expansion("einstein", 1),
+                expansion("einsten", 1),
+                expansion("einsteins", 2)
+            ]
+        );
+        assert_eq!(
+            expand_fuzzy("einstien", 2, 1),
+            vec![expansion("einstein", 1)]
+        );
+        assert_eq!(
+            expand_fuzzy("einstein", 0, 10),
+            vec![expansion("einstein", 0)]
+        );
+        assert_eq!(
+            expand_fuzzy("epstien", 1, 10),
+            vec![expansion("epstein", 1)]
+        );
+
+        // Exact tokens only match exact tokens
+        assert_eq!(
+            expand_fuzzy("=einstien", 1, 10),
+            vec![expansion("=einstien", 0), expansion("=einstein", 1)]
+        );
+        assert!(expand_fuzzy("newton", 2, 10).is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use std::{cmp::Ordering, cmp::Reverse, collections::BinaryHeap};
+
+// An article's score, ordered so better articles compare greater: higher scores first, then lower
+// article IDs so ties always come out in the same order
+#[derive(Debug, Clone, Copy)]
+struct ScoredArticle {
+    score: f64,
+    article_id: usize,
+}
+
+impl Ord for ScoredArticle {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then(other.article_id.cmp(&self.article_id))
+    }
+}
+
+impl PartialOrd for ScoredArticle {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ScoredArticle {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScoredArticle {}
+
+// The best scored articles pushed so far, at most `capacity` of them, or all of them without one.
+// The heap's top is the worst article kept, so a new one only needs comparing against it.
+pub struct TopScores {
+    capacity: Option<usize>,
+    heap: BinaryHeap<Reverse<ScoredArticle>>,
+}
+
+impl TopScores {
+    pub fn new(capacity: Option<usize>) -> Self {
+        TopScores {
+            capacity,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn push(&mut self, article_id: usize, score: f64) {
+        let scored_article = ScoredArticle { score, article_id };
+        match self.capacity {
+            Some(capacity) if self.heap.len() >= capacity => {
+                if let Some(mut worst) = self.heap.peek_mut() {
+                    if scored_article > worst.0 {
+                        *worst = Reverse(scored_article);
+                    }
+                }
+            }
+            _ => self.heap.push(Reverse(scored_article)),
+        }
+    }
+
+    // The score an article has to beat to be kept, once there's no room left. Nothing gets in
+    // without any room at all.
+    pub fn threshold(&self) -> Option<f64> {
+        match self.capacity {
+            Some(0) => Some(f64::INFINITY),
+            Some(capacity) if self.heap.len() >= capacity => {
+                self.heap.peek().map(|worst| worst.0.score)
+            }
+            _ => None,
+        }
+    }
+
+    // Best first
+    pub fn into_sorted_vec(self) -> Vec<(usize, f64)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(scored_article)| (scored_article.article_id, scored_article.score))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ties_keep_the_lowest_article_ids() {
+        let mut top_scores = TopScores::new(Some(2));
+        for article_id in [7, 3, 9, 5] {
+            top_scores.push(article_id, 1.0);
+        }
+        top_scores.push(1, 0.5);
+        assert_eq!(top_scores.threshold(), Some(1.0));
+        assert_eq!(top_scores.into_sorted_vec(), vec![(3, 1.0), (5, 1.0)]);
+    }
+}
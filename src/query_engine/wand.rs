@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use super::top_scores::TopScores;
+use crate::common::{block_index, BLOCK_SIZE, PRUNING_TOLERANCE};
+
+// Where a cursor reads a term's articles from, in increasing order
+pub trait ArticleIdSource {
+    // The first article at or after `article_id`
+    fn next_at_or_after(&self, article_id: usize) -> Option<usize>;
+}
+
+// The articles a query term occurs in, walked in increasing order, with upper bounds of its score.
+// A term scored in several fields reads the articles of each of them.
+pub struct PostingsCursor<'a> {
+    // Every source with the article it's on
+    sources: Vec<(&'a dyn ArticleIdSource, Option<usize>)>,
+    current: Option<usize>,
+    max_score: f64,
+    // Block -> upper bound of the term's score in it
+    block_max_scores: HashMap<usize, f64>,
+}
+
+impl<'a> PostingsCursor<'a> {
+    pub fn new(
+        sources: Vec<&'a dyn ArticleIdSource>,
+        block_max_scores: HashMap<usize, f64>,
+    ) -> Self {
+        let sources = sources
+            .into_iter()
+            .map(|source| (source, source.next_at_or_after(0)))
+            .collect::<Vec<(&dyn ArticleIdSource, Option<usize>)>>();
+        PostingsCursor {
+            current: sources.iter().filter_map(|(_, current)| *current).min(),
+            sources,
+            max_score: block_max_scores.values().copied().fold(0.0, f64::max),
+            block_max_scores,
+        }
+    }
+
+    fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    // Moves to the first article at or after `article_id`
+    fn seek(&mut self, article_id: usize) {
+        for (source, current) in &mut self.sources {
+            if current.is_some_and(|current| current < article_id) {
+                *current = source.next_at_or_after(article_id);
+            }
+        }
+        self.current = self
+            .sources
+            .iter()
+            .filter_map(|(_, current)| *current)
+            .min();
+    }
+
+    fn block_max_score(&self, block: usize) -> f64 {
+        self.block_max_scores.get(&block).copied().unwrap_or(0.0)
+    }
+}
+
+// Whether an article scoring at most `bound` could still make it into the top scores
+fn can_compete(bound: f64, threshold: Option<f64>) -> bool {
+    threshold.is_none_or(|threshold| bound + PRUNING_TOLERANCE * bound.abs().max(1.0) >= threshold)
+}
+
+// Block-Max WAND: scores only the articles whose upper bound, the sum of the bounds of the terms
+// they contain plus `extra_bound`, can beat the worst of the top scores so far. Cursors are kept
+// sorted by their current article. The pivot is the first article the bounds of the terms up to
+// it could get into the top scores, articles before it are skipped. Before moving any cursor onto
+// the pivot, the block maxes of those terms in the pivot's block are checked, and if they can't
+// make it either the cursors skip the rest of the block without reading its articles. `score`
+// returns None for articles that don't match the query.
+pub fn collect_top_scores(
+    mut cursors: Vec<PostingsCursor>,
+    extra_bound: f64,
+    top_scores: &mut TopScores,
+    mut score: impl FnMut(usize) -> Option<f64>,
+) {
+    loop {
+        cursors.retain(|cursor| cursor.current().is_some());
+        cursors.sort_by_key(|cursor| cursor.current());
+        let threshold = top_scores.threshold();
+
+        let mut bound = extra_bound;
+        let pivot = cursors.iter().position(|cursor| {
+            bound += cursor.max_score;
+            can_compete(bound, threshold)
+        });
+        let pivot_article_id = match pivot.and_then(|pivot| cursors[pivot].current()) {
+            Some(pivot_article_id) => pivot_article_id,
+            None => break,
+        };
+
+        // Only the terms up to the pivot (and any other on it) can occur in the pivot's block
+        // from the pivot on, until the next article of another term
+        let num_up_to_pivot = cursors
+            .iter()
+            .take_while(|cursor| cursor.current() <= Some(pivot_article_id))
+            .count();
+        let block = block_index(pivot_article_id);
+        let block_bound = extra_bound
+            + cursors[..num_up_to_pivot]
+                .iter()
+                .map(|cursor| cursor.block_max_score(block))
+                .sum::<f64>();
+        if !can_compete(block_bound, threshold) {
+            let next_article_id = cursors
+                .get(num_up_to_pivot)
+                .and_then(PostingsCursor::current)
+                .unwrap_or(usize::MAX)
+                .min((block + 1) * BLOCK_SIZE);
+            for cursor in &mut cursors[..num_up_to_pivot] {
+                cursor.seek(next_article_id);
+            }
+            continue;
+        }
+
+        // Articles before the pivot can't make it, whatever they contain
+        if cursors[0].current() != Some(pivot_article_id) {
+            for cursor in cursors
+                .iter_mut()
+                .take_while(|cursor| cursor.current() < Some(pivot_article_id))
+            {
+                cursor.seek(pivot_article_id);
+            }
+            continue;
+        }
+
+        if let Some(score) = score(pivot_article_id) {
+            top_scores.push(pivot_article_id, score);
+        }
+        for cursor in &mut cursors[..num_up_to_pivot] {
+            cursor.seek(pivot_article_id + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::common::Lcg;
+
+    // Sorted article IDs, recording every article they're asked to seek to
+    struct SortedIds {
+        article_ids: Vec<usize>,
+        seeks: RefCell<Vec<usize>>,
+    }
+
+    impl SortedIds {
+        fn new(mut article_ids: Vec<usize>) -> Self {
+            article_ids.sort_unstable();
+            SortedIds {
+                article_ids,
+                seeks: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl ArticleIdSource for SortedIds {
+        fn next_at_or_after(&self, article_id: usize) -> Option<usize> {
+            self.seeks.borrow_mut().push(article_id);
+            let position = self.article_ids.partition_point(|id| *id < article_id);
+            self.article_ids.get(position).copied()
+        }
+    }
+
+    // Term -> article ID -> the term's score in the article, spread over a few blocks. Scores
+    // only take a few values so plenty of articles tie.
+    fn term_scores() -> Vec<HashMap<usize, f64>> {
+        let mut random = Lcg::new(42);
+        (0..3)
+            .map(|_| {
+                (0..200)
+                    .map(|_| {
+                        (
+                            random.next_u64() as usize % (4 * BLOCK_SIZE),
+                            (random.next_u64() as usize % 3 + 1) as f64,
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Each term's articles split over two sources, like a term scored in two fields
+    fn sources(term_scores: &[HashMap<usize, f64>]) -> Vec<[SortedIds; 2]> {
+        term_scores
+            .iter()
+            .map(|scores| {
+                let (even, odd) = scores
+                    .keys()
+                    .partition::<Vec<usize>, _>(|article_id| article_id.is_multiple_of(2));
+                [SortedIds::new(even), SortedIds::new(odd)]
+            })
+            .collect()
+    }
+
+    fn cursors<'a>(
+        term_scores: &[HashMap<usize, f64>],
+        sources: &'a [[SortedIds; 2]],
+    ) -> Vec<PostingsCursor<'a>> {
+        term_scores
+            .iter()
+            .zip(sources)
+            .map(|(scores, sources)| {
+                let mut block_max_scores: HashMap<usize, f64> = HashMap::new();
+                for (article_id, score) in scores {
+                    let block_max = block_max_scores
+                        .entry(block_index(*article_id))
+                        .or_insert(0.0);
+                    *block_max = block_max.max(*score);
+                }
+                PostingsCursor::new(
+                    sources
+                        .iter()
+                        .map(|source| source as &dyn ArticleIdSource)
+                        .collect(),
+                    block_max_scores,
+                )
+            })
+            .collect()
+    }
+
+    // The extra score of every seventh article stands in for a proximity boost of at most 0.5,
+    // and every fifth article doesn't match
+    fn score(term_scores: &[HashMap<usize, f64>], article_id: usize) -> Option<f64> {
+        if article_id.is_multiple_of(5) {
+            return None;
+        }
+        let extra = if article_id.is_multiple_of(7) {
+            0.5
+        } else {
+            0.0
+        };
+        Some(
+            extra
+                + term_scores
+                    .iter()
+                    .filter_map(|scores| scores.get(&article_id))
+                    .sum::<f64>(),
+        )
+    }
+
+    #[test]
+    fn keeps_the_same_top_scores_as_scoring_every_article() {
+        let term_scores = term_scores();
+        let sources = sources(&term_scores);
+        let article_ids = term_scores
+            .iter()
+            .flat_map(|scores| scores.keys().copied())
+            .collect::<HashSet<usize>>();
+        for num_top in [0, 1, 5, 20, 100, 1000] {
+            let mut exhaustive = TopScores::new(Some(num_top));
+            for article_id in &article_ids {
+                if let Some(score) = score(&term_scores, *article_id) {
+                    exhaustive.push(*article_id, score);
+                }
+            }
+            let mut pruned = TopScores::new(Some(num_top));
+            collect_top_scores(
+                cursors(&term_scores, &sources),
+                0.5,
+                &mut pruned,
+                |article_id| score(&term_scores, article_id),
+            );
+            assert_eq!(pruned.into_sorted_vec(), exhaustive.into_sorted_vec());
+        }
+    }
+
+    #[test]
+    fn skips_blocks_that_cant_compete_without_reading_them() {
+        let first = SortedIds::new(vec![5]);
+        let second = SortedIds::new(
+            (BLOCK_SIZE + 1..BLOCK_SIZE + 100)
+                .chain([2 * BLOCK_SIZE + 3])
+                .collect(),
+        );
+        let cursors = vec![
+            PostingsCursor::new(vec![&first], HashMap::from([(0, 10.0)])),
+            PostingsCursor::new(vec![&second], HashMap::from([(1, 1.0), (2, 20.0)])),
+        ];
+        let mut scored = Vec::new();
+        let mut top_scores = TopScores::new(Some(1));
+        collect_top_scores(cursors, 0.0, &mut top_scores, |article_id| {
+            scored.push(article_id);
+            Some(if article_id == 5 { 10.0 } else { 20.0 })
+        });
+        assert_eq!(scored, vec![5, 2 * BLOCK_SIZE + 3]);
+        assert_eq!(
+            second.seeks.into_inner(),
+            vec![0, 2 * BLOCK_SIZE, 2 * BLOCK_SIZE + 4]
+        );
+        assert_eq!(
+            top_scores.into_sorted_vec(),
+            vec![(2 * BLOCK_SIZE + 3, 20.0)]
+        );
+    }
+
+    // Bounds add up scores in another order than the articles' scores do, so they can come out a
+    // rounding error below a score they should bound
+    #[test]
+    fn keeps_articles_a_rounding_error_above_their_bound() {
+        let first = SortedIds::new(vec![1]);
+        let second = SortedIds::new(vec![BLOCK_SIZE]);
+        let cursors = vec![
+            PostingsCursor::new(vec![&first], HashMap::from([(0, 1.0)])),
+            PostingsCursor::new(vec![&second], HashMap::from([(1, 1.0 - f64::EPSILON)])),
+        ];
+        let mut top_scores = TopScores::new(Some(1));
+        collect_top_scores(cursors, 0.0, &mut top_scores, |article_id| {
+            Some(if article_id == 1 {
+                1.0
+            } else {
+                1.0 + f64::EPSILON
+            })
+        });
+        assert_eq!(
+            top_scores.into_sorted_vec(),
+            vec![(BLOCK_SIZE, 1.0 + f64::EPSILON)]
+        );
+    }
+}